@@ -0,0 +1,49 @@
+use std::hint::black_box;
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion};
+use sagakar_raycasting::bench::{DenseDecorations, Scene};
+
+fn cast_rays(c: &mut Criterion) {
+    let scene = Scene::load(640.0);
+    c.bench_function("cast 640 rays", |b| b.iter(|| black_box(scene.cast_rays())));
+}
+
+fn cast_rays_serial_vs_parallel(c: &mut Criterion) {
+    let scene = Scene::load(1920.0);
+    let mut group = c.benchmark_group("cast 1920 rays");
+    group.bench_function(BenchmarkId::from_parameter("serial"), |b| b.iter(|| black_box(scene.cast_rays_serially())));
+    group.bench_function(BenchmarkId::from_parameter("parallel"), |b| b.iter(|| black_box(scene.cast_rays())));
+    group.finish();
+}
+
+fn ray_directions_standing_still(c: &mut Criterion) {
+    let mut scene = Scene::load(640.0);
+    let mut group = c.benchmark_group("cast 640 rays standing still");
+    group.bench_function(BenchmarkId::from_parameter("cached directions"), |b| b.iter(|| black_box(scene.cast_rays_from_cache())));
+    group.bench_function(BenchmarkId::from_parameter("recomputed directions"), |b| b.iter(|| black_box(scene.cast_rays_reaiming())));
+    group.finish();
+}
+
+fn cast_walls(c: &mut Criterion) {
+    let scene = Scene::load(640.0);
+    // Criterion only times things, so the draw calls saved by batching are reported alongside
+    let (batched, per_slice) = scene.wall_draw_calls();
+    println!("Wall draw calls at 640 columns: {batched} batched, {per_slice} with one per slice");
+    c.bench_function("cast walls at 640 columns", |b| b.iter(|| black_box(scene.cast_walls())));
+}
+
+fn cast_floor_and_ceiling(c: &mut Criterion) {
+    let scene = Scene::load(640.0);
+    c.bench_function("cast floor and ceiling at 640x360", |b| b.iter(|| black_box(scene.cast_flats())));
+}
+
+fn decorations_in_view(c: &mut Criterion) {
+    // A decoration in every tile, well past the count where levels get a grid
+    let level = DenseDecorations::load(128);
+    let mut group = c.benchmark_group("project decorations of a dense 128x128 level");
+    group.bench_function(BenchmarkId::from_parameter("grid"), |b| b.iter(|| black_box(level.project_from_grid())));
+    group.bench_function(BenchmarkId::from_parameter("every one"), |b| b.iter(|| black_box(level.project_all())));
+    group.finish();
+}
+
+criterion_group!(benches, cast_rays, cast_rays_serial_vs_parallel, ray_directions_standing_still, cast_walls, cast_floor_and_ceiling, decorations_in_view);
+criterion_main!(benches);
@@ -0,0 +1,150 @@
+use ggez::{audio::{SoundSource, Source, SpatialSource}, glam::Vec2, Context};
+
+// Footstep sounds for each floor texture, indexed the same way as the floor layer of a map:
+// index 0 is the level's default floor, the rest line up with the `flats` in the texture manifest
+const FOOTSTEP_PATHS: [&str; 4] = [
+    "/sounds/step_default.wav",
+    "/sounds/step_stone.wav",
+    "/sounds/step_brick.wav",
+    "/sounds/step_wood.wav",
+];
+const MUSIC_PATH: &str = "/sounds/music.wav"; // Looped for as long as the game runs
+const BUMP_PATH: &str = "/sounds/bump.wav"; // Played when the player walks into a wall
+const VOLUME_STEP: f32 = 0.1; // How much the volume keys change the music volume by
+const AMBIENT_DISTANCE: f32 = 10.0; // Ambient sounds fade out linearly and are silent past this distance, in units
+const EAR_SPACING: f32 = 0.2; // Distance from the middle of the listener's head to each ear, in units
+
+/// Every sound the game plays, loaded once at startup.
+/// Sounds that fail to load are reported and then left silent, so a missing file never stops the game.
+pub struct Audio {
+    footsteps: Vec<Option<Source>>,
+    bump: Option<Source>,
+    music: Option<Source>,
+    music_volume: f32, // From 0 to 1
+    muted: bool,
+}
+
+impl Audio {
+    pub fn load(ctx: &Context, music_volume: f32) -> Audio {
+        let footsteps = FOOTSTEP_PATHS.iter().map(|path| load_source(ctx, path)).collect();
+        let bump = load_source(ctx, BUMP_PATH);
+        let music = load_source(ctx, MUSIC_PATH);
+        Audio { footsteps, bump, music, music_volume: music_volume.clamp(0.0, 1.0), muted: false }
+    }
+
+    /// Starts looping the music, if it loaded
+    pub fn start_music(&mut self, ctx: &Context) {
+        let volume = self.effective_music_volume();
+        if let Some(music) = &mut self.music {
+            music.set_repeat(true);
+            music.set_volume(volume);
+            if let Err(error) = music.play(ctx) {
+                eprintln!("Warning: failed playing music: {error}");
+            }
+        }
+    }
+
+    pub fn music_volume(&self) -> f32 {
+        self.music_volume
+    }
+
+    pub fn muted(&self) -> bool {
+        self.muted
+    }
+
+    /// Raises or lowers the music volume by `steps` volume steps, unmuting it
+    pub fn change_music_volume(&mut self, steps: f32) {
+        self.music_volume = (self.music_volume + steps * VOLUME_STEP).clamp(0.0, 1.0);
+        self.muted = false;
+        self.apply_music_volume();
+    }
+
+    pub fn toggle_mute(&mut self) {
+        self.muted = !self.muted;
+        self.apply_music_volume();
+    }
+
+    fn effective_music_volume(&self) -> f32 {
+        match self.muted {
+            true => 0.0,
+            false => self.music_volume,
+        }
+    }
+
+    fn apply_music_volume(&mut self) {
+        let volume = self.effective_music_volume();
+        if let Some(music) = &mut self.music {
+            music.set_volume(volume);
+        }
+    }
+
+    /// Plays a footstep for walking on the given floor texture,
+    /// falling back to the default floor sound for textures without one
+    pub fn play_footstep(&mut self, ctx: &Context, floor_texture: usize) {
+        let index = match floor_texture < self.footsteps.len() {
+            true => floor_texture,
+            false => 0,
+        };
+        if let Some(source) = &mut self.footsteps[index] {
+            // Detached so quick steps can overlap instead of cutting each other off
+            if let Err(error) = source.play_detached(ctx) {
+                eprintln!("Warning: failed playing footstep sound: {error}");
+            }
+        }
+    }
+
+    /// Plays the soft bump of walking into a wall
+    pub fn play_bump(&mut self, ctx: &Context) {
+        if let Some(source) = &mut self.bump {
+            if let Err(error) = source.play_detached(ctx) {
+                eprintln!("Warning: failed playing bump sound: {error}");
+            }
+        }
+    }
+}
+
+fn load_source(ctx: &Context, path: &str) -> Option<Source> {
+    match Source::new(ctx, path) {
+        Ok(source) => Some(source),
+        Err(error) => {
+            eprintln!("Warning: failed loading sound {path}: {error}");
+            None
+        }
+    }
+}
+
+/// A looping sound coming from a point in the level, like a crackling torch.
+/// It gets quieter with distance and pans towards the side of the listener it's on.
+pub struct AmbientSound {
+    source: SpatialSource,
+}
+
+impl AmbientSound {
+    /// Loads the sound at `path` and starts looping it, or reports the error and returns `None`
+    pub fn load(ctx: &Context, path: &str) -> Option<AmbientSound> {
+        let mut source = match SpatialSource::new(ctx, ctx, path) {
+            Ok(source) => source,
+            Err(error) => {
+                eprintln!("Warning: failed loading sound {path}: {error}");
+                return None;
+            }
+        };
+        source.set_ears([-EAR_SPACING, 0.0, 0.0], [EAR_SPACING, 0.0, 0.0]);
+        source.set_repeat(true);
+        source.set_volume(0.0);
+        if let Err(error) = source.play(ctx) {
+            eprintln!("Warning: failed playing sound {path}: {error}");
+        }
+        Some(AmbientSound { source })
+    }
+
+    /// Updates the volume and panning from where the sound is relative to the listener,
+    /// with x pointing to the listener's right and y pointing forward, in units
+    pub fn update(&mut self, relative_position: Vec2) {
+        let volume = (1.0 - relative_position.length() / AMBIENT_DISTANCE).max(0.0);
+        self.source.set_volume(volume);
+        // Only the direction is passed on for panning, the fading with distance is done by the volume
+        let direction = relative_position.normalize_or_zero();
+        self.source.set_position([direction.x, 0.0, direction.y]);
+    }
+}
@@ -0,0 +1,178 @@
+//! Entry points for the benchmarks in `benches/`, which can only reach what the library exports.
+//! Everything runs on the shipped map from a fixed pose, without a window.
+
+use std::collections::HashSet;
+use crate::level::{Level, RayHit, cast_ray_through, decoration_grid, grid_in_view};
+use crate::parsing::{map_section, tile_legend};
+use crate::player::Player;
+use crate::render::{cast_flats, cast_walls, sort_far_to_near};
+use crate::config::Config;
+use ggez::glam::{vec2, Vec2};
+use rayon::prelude::*;
+
+const MAP: &str = include_str!("../map.txt");
+// In the corridor between the two rooms, looking down into the second one, so rays go both near and far
+const POSITION: Vec2 = vec2(7.5, 12.5);
+const DIRECTION: Vec2 = vec2(0.0, 1.0);
+
+/// The shipped level seen from a fixed pose, ready to be rendered
+pub struct Scene {
+    level: Level,
+    player: Player,
+    config: Config,
+    ray_directions: Vec<Vec2>,
+    wall_spans: Vec<(f32, f32)>,
+}
+
+impl Scene {
+    /// Sets up the scene for a screen `x_resolution` columns wide, at the default aspect ratio
+    pub fn load(x_resolution: f32) -> Scene {
+        let defaults = Config::default();
+        let config = Config {
+            x_resolution,
+            y_resolution: (x_resolution * defaults.y_resolution / defaults.x_resolution).round(),
+            ..defaults
+        };
+        let tiles_str = map_section(MAP, None).unwrap_or_default();
+        let level = Level::from_tiles(tiles_str, &tile_legend(&config));
+        let player = Player::new(POSITION, DIRECTION, config.camera_plane_length());
+        // With no walls in the way every pixel of the floor and ceiling is cast, like when looking across open ground
+        let horizon = player.horizon(config.y_resolution);
+        let wall_spans = vec![(horizon, horizon); x_resolution as usize];
+        let ray_directions = vec![Vec2::ZERO; x_resolution as usize];
+        let mut scene = Scene { level, player, config, ray_directions, wall_spans };
+        scene.aim_rays();
+        scene
+    }
+
+    /// Casts every column like a frame where the player stands still, which reuses the ray directions of the last frame
+    pub fn cast_rays_from_cache(&mut self) -> usize {
+        if self.player.rays_dirty {
+            self.aim_rays();
+        }
+        self.cast_rays()
+    }
+
+    /// Casts every column like a frame that rebuilds the ray directions whether or not the player turned
+    pub fn cast_rays_reaiming(&mut self) -> usize {
+        self.aim_rays();
+        self.cast_rays()
+    }
+
+    fn aim_rays(&mut self) {
+        self.player.aim_rays(&mut self.ray_directions, self.config.x_resolution);
+        self.player.rays_dirty = false;
+    }
+
+    /// Casts a ray for every column in parallel like the renderer does, returning how many walls they hit
+    pub fn cast_rays(&self) -> usize {
+        self.column_hits().iter().map(Vec::len).sum()
+    }
+
+    /// Casts the same rays as `cast_rays`, one column after another on this thread
+    pub fn cast_rays_serially(&self) -> usize {
+        self.column_hits_serially().iter().map(Vec::len).sum()
+    }
+
+    fn column_hits(&self) -> Vec<Vec<RayHit>> {
+        self.ray_directions.par_iter().map(|ray_direction| self.cast(*ray_direction)).collect()
+    }
+
+    fn column_hits_serially(&self) -> Vec<Vec<RayHit>> {
+        self.ray_directions.iter().map(|ray_direction| self.cast(*ray_direction)).collect()
+    }
+
+    fn cast(&self, ray_direction: Vec2) -> Vec<RayHit> {
+        let (map, doors, secret_walls) = (&self.level.map, &self.level.doors, &self.level.secret_walls);
+        cast_ray_through(map, doors, secret_walls, self.player.position, ray_direction, self.config.render_distance)
+    }
+
+    /// Turns the hits of every column into wall slices like the renderer does, returning how many there are
+    pub fn cast_walls(&self) -> usize {
+        let column_hits = self.column_hits_serially();
+        let mut slices = 0;
+        cast_walls(&self.level, &self.config, &self.player, &self.ray_directions, &column_hits, None, |_| slices += 1);
+        slices
+    }
+
+    /// How many draw calls the walls take with the backmost walls batched by texture,
+    /// and how many they took with a draw call for every slice
+    pub fn wall_draw_calls(&self) -> (usize, usize) {
+        let column_hits = self.column_hits_serially();
+        let (mut slices, mut unbatched) = (0, 0);
+        let mut batches = HashSet::new();
+        cast_walls(&self.level, &self.config, &self.player, &self.ray_directions, &column_hits, None, |slice| {
+            slices += 1;
+            match slice.batched {
+                true => batches.insert(slice.texture_index),
+                false => {
+                    unbatched += 1;
+                    false
+                }
+            };
+        });
+        (unbatched + batches.len(), slices)
+    }
+
+    /// Casts the floor and ceiling like the renderer does, returning how many pixels were drawn
+    pub fn cast_flats(&self) -> usize {
+        let mut pixels = 0;
+        cast_flats(&self.level, &self.config, &self.player, &self.wall_spans, None, 0.0, |_, _| pixels += 1);
+        pixels
+    }
+}
+
+/// An open level with a decoration standing in every tile
+pub struct DenseDecorations {
+    positions: Vec<Vec2>,
+    grid: Vec<Vec<Vec<usize>>>,
+    player: Player,
+    render_distance: f32,
+}
+
+impl DenseDecorations {
+    /// Sets up a level `size` tiles across, with the player standing in the middle of it
+    pub fn load(size: usize) -> DenseDecorations {
+        let config = Config::default();
+        let map = vec![vec![None; size]; size];
+        let positions = (0..size * size)
+            .map(|index| vec2((index % size) as f32 + 0.5, (index / size) as f32 + 0.5))
+            .collect::<Vec<Vec2>>();
+        let grid = decoration_grid(positions.iter().copied(), &map);
+        let middle = size as f32 / 2.0;
+        let player = Player::new(vec2(middle, middle), DIRECTION, config.camera_plane_length());
+        DenseDecorations { positions, grid, player, render_distance: config.render_distance }
+    }
+
+    /// Sorts and projects the decorations in the tiles in view like the renderer does, returning how many are in front of the player
+    pub fn project_from_grid(&self) -> usize {
+        self.project(grid_in_view(&self.grid, &self.player, self.render_distance))
+    }
+
+    /// Sorts and projects every decoration like the renderer does for levels without a grid
+    pub fn project_all(&self) -> usize {
+        self.project((0..self.positions.len()).collect())
+    }
+
+    fn project(&self, mut indices: Vec<usize>) -> usize {
+        sort_far_to_near(&mut indices, self.player.position, |index| self.positions[*index]);
+        indices.iter().filter(|index| self.player.to_camera_space(self.positions[**index]).y > 0.0).count()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parallel_casting_matches_serial() {
+        let scene = Scene::load(1920.0);
+        assert!(scene.column_hits() == scene.column_hits_serially());
+    }
+
+    #[test]
+    fn batching_walls_takes_fewer_draw_calls() {
+        let (batched, per_slice) = Scene::load(640.0).wall_draw_calls();
+        assert!(batched < per_slice);
+    }
+}
@@ -0,0 +1,433 @@
+use std::collections::HashMap;
+use ggez::{graphics::Color, input::keyboard::KeyCode, GameError};
+use serde::Deserialize;
+
+/// Gameplay and rendering parameters, loaded from a TOML file.
+/// Any field missing from the file falls back to its default.
+#[derive(Deserialize)]
+#[serde(default)]
+pub struct Config {
+    // Gameplay parameters
+    pub move_speed: f32, // In units / second
+    pub rotation_speed: f32, // In radians / second
+    pub gamepad_deadzone: f32, // How far sticks can be pushed before they register, from 0 to 1
+    pub pickup_radius: f32, // How close the player has to get to an item to pick it up, in units
+    pub music_volume: f32, // From 0 to 1. Changed in game with the volume keys, and saved back to the file on pause or quit
+
+    // Rendering parameters
+    pub x_resolution: f32,
+    pub y_resolution: f32,
+    pub field_of_view: f32, // Vertical, as half the height of the view at a distance of one unit. 0.5 works out to ~53 degrees
+    pub texture_size: u32,
+    pub floor_parallax: Option<String>, // A distant texture to draw the floor with instead of the tiled one, e.g. "/textures/ground.png". Left out for tiles
+    pub ceiling_parallax: Option<String>, // The same for the ceiling. Both are overridden by their gradient
+    pub sky: Vec<SkyLayer>, // Panoramic layers to draw above the horizon instead of the ceiling, farthest first. Overrides `ceiling_parallax`
+    pub parallax_factor: f32, // How far parallax floors and ceilings shift as the player moves, relative to tiled ones
+    pub fog_distance: f32, // Distance at which walls and floors are fully fogged
+    pub fog_color: [f32; 3], // Works best for dark colors, as fog is applied by tinting
+    pub render_distance: f32, // Walls farther away than this aren't drawn, in units
+    pub vsync: bool, // Waits for the display to refresh before showing each frame, which also caps the frame rate at its refresh rate
+    pub max_fps: Option<f32>, // Frames per second the game sleeps to stay under. Left out for no cap
+    pub nearest_sampling: bool, // Sharp pixels when true, smoothed when false. Toggled in game with N
+    pub ambient_occlusion: bool, // Darkens the floor along the base of walls and in corners, at the cost of a few map lookups per floor pixel
+    pub gradient_ceiling: bool, // Fills the ceiling with a gradient from `horizon_color` to `zenith_color` instead of textures
+    pub gradient_floor: bool, // Fills the floor with the same gradient, mirrored
+    pub horizon_color: [f32; 3],
+    pub zenith_color: [f32; 3],
+    pub flashlight_intensity: f32, // Brightness the flashlight adds at the center of its beam, on top of the dimmed surroundings. Toggled in game with F
+    pub brightness: f32, // Multiplies the color of everything in the world. Changed in game with the brightness keys, and saved back to the file on pause or quit
+    pub minimap_scale: f32, // In pixels / tile
+    pub minimap_position: [f32; 2], // Top left corner, in pixels
+
+    // Misc parameters
+    pub map_path: String, // An ASCII map, or a PNG image whose pixel colors are looked up in `map_palette`
+    pub tile_legend: HashMap<char, LegendTextures>, // Extra map characters for walls, mapped to their texture indices
+    pub map_palette: HashMap<char, [u8; 3]>, // The RGB color each map character is drawn with in image maps
+    pub liquids: HashMap<char, Liquid>, // The liquids the `[liquids]` layer of a map can flood floor tiles with, by map character
+    pub decoration_legend: HashMap<char, LegendDecoration>, // The decorations the `[decorations]` layer of a map can place, by map character
+    pub item_legend: HashMap<char, LegendItem>, // The items the `[items]` layer of a map can place, by map character
+    pub light_legend: HashMap<char, LegendLight>, // The lights the `[lights]` layer of a map can place, by map character
+    pub weapons: Vec<WeaponSettings>, // The weapons the player carries, set as `[[weapons]]` tables and selected with the weapon keys in order
+    pub mouse: MouseSettings,
+    pub keys: KeyBindings,
+}
+
+/// How moving the mouse turns the view, set under `[mouse]`
+#[derive(Deserialize)]
+#[serde(default)]
+pub struct MouseSettings {
+    pub sensitivity: f32, // In radians / pixel, for turning as well as looking up and down
+    pub invert_y: bool, // Moving the mouse forward looks down instead of up
+    pub smoothing: bool, // Averages the movement of the last few updates, steadying jittery mice at the cost of a little lag
+    pub smoothing_frames: usize, // How many updates smoothing averages over
+}
+
+impl Default for MouseSettings {
+    fn default() -> Self {
+        MouseSettings {
+            sensitivity: 0.003,
+            invert_y: false,
+            smoothing: false,
+            smoothing_frames: 3,
+        }
+    }
+}
+
+/// The textures of a wall in the tile legend, either one index for every face
+/// or four indices for the north, east, south and west faces
+#[derive(Deserialize)]
+#[serde(untagged)]
+pub enum LegendTextures {
+    All(usize),
+    Faces([usize; 4]),
+}
+
+/// A liquid pooled on the floor, drawn as a translucent tint over the floor texture
+#[derive(Deserialize)]
+#[serde(default)]
+pub struct Liquid {
+    pub color: [f32; 3],
+    pub opacity: f32, // How much the tint covers up the floor texture, from 0 to 1
+    pub shimmer: f32, // How far the opacity ripples up and down, as a fraction of it. 0 is a still surface
+    pub speed: f32, // Multiplies the move speed of the player wading through it
+}
+
+impl Default for Liquid {
+    fn default() -> Self {
+        Liquid { color: [0.1, 0.3, 0.8], opacity: 0.5, shimmer: 0.15, speed: 0.6 }
+    }
+}
+
+/// A decoration in the decoration legend
+#[derive(Deserialize)]
+#[serde(default)]
+pub struct LegendDecoration {
+    pub sprite: String, // e.g. "/cat.png"
+    pub scale: f32,
+    pub vertical_offset: f32, // How far above (positive) or below (negative) eye level it floats, in units
+    pub sound: Option<String>, // Looped from where the decoration stands, e.g. "/sounds/purr.wav". Left out for silence
+}
+
+impl Default for LegendDecoration {
+    fn default() -> Self {
+        LegendDecoration { sprite: String::new(), scale: 1.0, vertical_offset: 0.0, sound: None }
+    }
+}
+
+/// An item in the item legend. Items are coins unless they open doors
+#[derive(Deserialize)]
+#[serde(default)]
+pub struct LegendItem {
+    pub sprite: String, // e.g. "/coin.png"
+    pub scale: f32,
+    pub key: Option<u32>, // The id of the locked doors this key opens. Left out for coins
+}
+
+impl Default for LegendItem {
+    fn default() -> Self {
+        LegendItem { sprite: String::new(), scale: 1.0, key: None }
+    }
+}
+
+/// A light in the light legend
+#[derive(Deserialize)]
+#[serde(default)]
+pub struct LegendLight {
+    pub color: [f32; 3],
+    pub radius: f32, // In units
+}
+
+impl Default for LegendLight {
+    fn default() -> Self {
+        LegendLight { color: [1.0, 1.0, 1.0], radius: 4.0 }
+    }
+}
+
+/// A weapon the player carries, set as a `[[weapons]]` table
+#[derive(Deserialize)]
+#[serde(default)]
+pub struct WeaponSettings {
+    pub sprites: String, // Directory of the frames, named `<frame>.png`, where frame 0 is shown while idle and the rest play once when firing
+    pub fire_rate: f32, // In shots / second
+    pub damage: u32, // Health taken from an enemy per hit
+}
+
+impl Default for WeaponSettings {
+    fn default() -> Self {
+        WeaponSettings { sprites: String::new(), fire_rate: 2.0, damage: 1 }
+    }
+}
+
+/// One layer of a panoramic sky, set as a `[[sky]]` table
+#[derive(Deserialize)]
+#[serde(default)]
+pub struct SkyLayer {
+    pub image: String, // e.g. "/textures/clouds.png"
+    pub scroll: f32, // How many times the image scrolls by per full turn. Higher for nearer layers, and whole so it wraps seamlessly
+}
+
+impl Default for SkyLayer {
+    fn default() -> Self {
+        SkyLayer { image: String::new(), scroll: 1.0 }
+    }
+}
+
+/// The key bound to each action, named like ggez's `KeyCode` variants, e.g. `"W"` or `"Space"`.
+/// An action without a key simply can't be performed.
+#[derive(Deserialize)]
+#[serde(default)]
+pub struct KeyBindings {
+    pub forward: Option<KeyCode>,
+    pub back: Option<KeyCode>,
+    pub turn_left: Option<KeyCode>,
+    pub turn_right: Option<KeyCode>,
+    pub strafe_left: Option<KeyCode>,
+    pub strafe_right: Option<KeyCode>,
+    pub sprint: Option<KeyCode>,
+    pub zoom: Option<KeyCode>,
+    pub use_door: Option<KeyCode>,
+    pub toggle_minimap: Option<KeyCode>,
+    pub toggle_sampling: Option<KeyCode>,
+    pub toggle_noclip: Option<KeyCode>,
+    pub toggle_flashlight: Option<KeyCode>,
+    pub toggle_debug: Option<KeyCode>,
+    pub screenshot: Option<KeyCode>,
+    pub pause: Option<KeyCode>,
+    pub reload_level: Option<KeyCode>,
+    pub volume_up: Option<KeyCode>,
+    pub volume_down: Option<KeyCode>,
+    pub toggle_mute: Option<KeyCode>,
+    pub brightness_up: Option<KeyCode>,
+    pub brightness_down: Option<KeyCode>,
+    pub fov_up: Option<KeyCode>,
+    pub fov_down: Option<KeyCode>,
+    pub quicksave: Option<KeyCode>,
+    pub quickload: Option<KeyCode>,
+    pub select_weapon: Vec<KeyCode>, // One key per weapon, in the order the weapons are listed in the game
+}
+
+impl Default for KeyBindings {
+    fn default() -> Self {
+        KeyBindings {
+            forward: Some(KeyCode::W),
+            back: Some(KeyCode::S),
+            turn_left: Some(KeyCode::A),
+            turn_right: Some(KeyCode::D),
+            strafe_left: Some(KeyCode::Q),
+            strafe_right: Some(KeyCode::E),
+            sprint: Some(KeyCode::LShift),
+            zoom: Some(KeyCode::Z),
+            use_door: Some(KeyCode::Space),
+            toggle_minimap: Some(KeyCode::M),
+            toggle_sampling: Some(KeyCode::N),
+            toggle_noclip: Some(KeyCode::V),
+            toggle_flashlight: Some(KeyCode::F),
+            toggle_debug: Some(KeyCode::F3),
+            screenshot: Some(KeyCode::F2),
+            pause: Some(KeyCode::Escape),
+            reload_level: Some(KeyCode::R),
+            volume_up: Some(KeyCode::Equals),
+            volume_down: Some(KeyCode::Minus),
+            toggle_mute: Some(KeyCode::U),
+            brightness_up: Some(KeyCode::RBracket),
+            brightness_down: Some(KeyCode::LBracket),
+            fov_up: Some(KeyCode::Period),
+            fov_down: Some(KeyCode::Comma),
+            quicksave: Some(KeyCode::F5),
+            quickload: Some(KeyCode::F9),
+            select_weapon: vec![KeyCode::Key1, KeyCode::Key2, KeyCode::Key3],
+        }
+    }
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            move_speed: 2.5,
+            rotation_speed: 1.6,
+            gamepad_deadzone: 0.15,
+            pickup_radius: 0.5,
+            music_volume: 0.6,
+            x_resolution: 640.0,
+            y_resolution: 360.0,
+            field_of_view: 0.5,
+            texture_size: 64,
+            floor_parallax: None,
+            ceiling_parallax: None,
+            sky: Vec::new(),
+            parallax_factor: 0.1,
+            fog_distance: 12.0,
+            fog_color: [0.0, 0.0, 0.0],
+            render_distance: 32.0,
+            vsync: true,
+            max_fps: None,
+            nearest_sampling: true,
+            ambient_occlusion: true,
+            gradient_ceiling: false,
+            gradient_floor: false,
+            horizon_color: [0.45, 0.5, 0.6],
+            zenith_color: [0.1, 0.12, 0.25],
+            flashlight_intensity: 1.0,
+            brightness: 1.0,
+            minimap_scale: 4.0,
+            minimap_position: [8.0, 24.0],
+            map_path: String::from("map.txt"),
+            tile_legend: HashMap::new(),
+            map_palette: HashMap::from([
+                ('.', [255, 255, 255]),
+                ('S', [0, 0, 0]),
+                ('B', [128, 0, 0]),
+                ('W', [128, 64, 0]),
+                ('L', [128, 128, 128]),
+                ('D', [0, 0, 255]),
+                ('G', [0, 128, 0]),
+                ('^', [255, 0, 0]), // Spawn, facing up
+            ]),
+            liquids: HashMap::from([
+                ('w', Liquid::default()), // Water
+                ('l', Liquid { color: [1.0, 0.35, 0.05], opacity: 0.75, shimmer: 0.2, speed: 0.4 }), // Lava
+                ('a', Liquid { color: [0.35, 0.9, 0.1], opacity: 0.6, shimmer: 0.1, speed: 0.5 }), // Acid
+            ]),
+            decoration_legend: HashMap::from([
+                ('c', LegendDecoration { sprite: String::from("/cat.png"), scale: 4.0, sound: Some(String::from("/sounds/purr.wav")), ..LegendDecoration::default() }),
+            ]),
+            item_legend: HashMap::from([
+                ('$', LegendItem { sprite: String::from("/coin.png"), scale: 2.0, key: None }),
+                ('1', LegendItem { sprite: String::from("/key.png"), scale: 2.0, key: Some(1) }),
+                ('2', LegendItem { sprite: String::from("/key.png"), scale: 2.0, key: Some(2) }),
+                ('3', LegendItem { sprite: String::from("/key.png"), scale: 2.0, key: Some(3) }),
+            ]),
+            light_legend: HashMap::from([
+                ('t', LegendLight { color: [0.9, 0.5, 0.15], radius: 4.0 }), // Torch
+                ('l', LegendLight { color: [0.6, 0.6, 0.55], radius: 6.0 }), // Lamp
+            ]),
+            weapons: vec![
+                WeaponSettings { sprites: String::from("/weapons/pistol"), fire_rate: 3.0, damage: 1 },
+                WeaponSettings { sprites: String::from("/weapons/shotgun"), fire_rate: 1.2, damage: 3 },
+                WeaponSettings { sprites: String::from("/weapons/chaingun"), fire_rate: 10.0, damage: 1 },
+            ],
+            mouse: MouseSettings::default(),
+            keys: KeyBindings::default(),
+        }
+    }
+}
+
+impl Config {
+    /// Loads the config from `path`, using the defaults if the file doesn't exist
+    pub fn load(path: &str) -> Result<Config, GameError> {
+        let config_str = match std::fs::read_to_string(path) {
+            Ok(config_str) => config_str,
+            Err(error) if error.kind() == std::io::ErrorKind::NotFound => return Ok(Config::default()),
+            Err(error) => return Err(GameError::ConfigError(format!("Failed reading {path}: {error}"))),
+        };
+        toml::from_str(&config_str)
+            .map_err(|error| GameError::ConfigError(format!("Failed parsing {path}: {error}")))
+    }
+
+    /// Writes the settings that can be changed in game, `music_volume` and `brightness`,
+    /// into the config file at `path`, creating it if needed.
+    /// Only those settings are touched, so everything else in the file is kept as the player wrote it.
+    pub fn save_settings(&self, path: &str) -> Result<(), GameError> {
+        let mut table = match std::fs::read_to_string(path) {
+            Ok(config_str) => config_str.parse::<toml::Table>()
+                .map_err(|error| GameError::ConfigError(format!("Failed parsing {path}: {error}")))?,
+            Err(error) if error.kind() == std::io::ErrorKind::NotFound => toml::Table::new(),
+            Err(error) => return Err(GameError::ConfigError(format!("Failed reading {path}: {error}"))),
+        };
+        table.insert(String::from("music_volume"), toml::Value::Float(self.music_volume as f64));
+        table.insert(String::from("brightness"), toml::Value::Float(self.brightness as f64));
+        std::fs::write(path, table.to_string())
+            .map_err(|error| GameError::ConfigError(format!("Failed writing {path}: {error}")))
+    }
+
+    /// Half the width of the view at a distance of one unit, which is the length of the camera plane.
+    /// The view is as much wider than it is tall as the screen is, so perspective stays square
+    /// at any resolution.
+    pub fn camera_plane_length(&self) -> f32 {
+        self.field_of_view * self.x_resolution / self.y_resolution
+    }
+
+    /// How wide the view is, in degrees
+    pub fn horizontal_fov(&self) -> f32 {
+        2.0 * self.camera_plane_length().atan().to_degrees()
+    }
+
+    /// Sets `field_of_view` so the view is `degrees` wide at the current resolution
+    pub fn set_horizontal_fov(&mut self, degrees: f32) {
+        self.field_of_view = (degrees.to_radians() / 2.0).tan() * self.y_resolution / self.x_resolution;
+    }
+
+    /// The width of a single texture pixel as a fraction of the whole texture
+    pub fn pixel_frac(&self) -> f32 {
+        1.0 / self.texture_size as f32
+    }
+
+    /// The color to tint something `distance` units away with to fade it into the fog, brightness included
+    pub fn fog(&self, distance: f32) -> Color {
+        let visibility = (1.0 - distance / self.fog_distance).clamp(0.0, 1.0);
+        let [r, g, b] = self.fog_color.map(|channel| (visibility + (1.0 - visibility) * channel) * self.brightness);
+        Color::new(r, g, b, 1.0)
+    }
+
+    /// The fog color as a ggez `Color`, brightness included so fully fogged walls still blend into it
+    pub fn fog_color(&self) -> Color {
+        let [r, g, b] = self.fog_color.map(|channel| channel * self.brightness);
+        Color::new(r, g, b, 1.0)
+    }
+
+    /// The color of the ceiling and floor gradient `t` of the way from the horizon to the edge of the screen, brightness included
+    pub fn gradient_color(&self, t: f32) -> Color {
+        let [r, g, b] = [0, 1, 2].map(|channel| {
+            let horizon = self.horizon_color[channel];
+            (horizon + (self.zenith_color[channel] - horizon) * t) * self.brightness
+        });
+        Color::new(r, g, b, 1.0)
+    }
+
+    /// The color to tint things that aren't fogged with, so they match the brightness of everything else
+    pub fn brightness_tint(&self) -> Color {
+        Color::new(self.brightness, self.brightness, self.brightness, 1.0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn camera_plane_widens_with_the_aspect_ratio() {
+        let wide = Config { x_resolution: 640.0, y_resolution: 360.0, ..Config::default() };
+        let square = Config { x_resolution: 360.0, y_resolution: 360.0, ..Config::default() };
+        assert_eq!(square.camera_plane_length(), square.field_of_view);
+        assert!((wide.camera_plane_length() - wide.field_of_view * 16.0 / 9.0).abs() < 0.0001);
+    }
+
+    #[test]
+    fn horizontal_fov_round_trips() {
+        for (x_resolution, y_resolution) in [(640.0, 360.0), (640.0, 480.0)] {
+            let mut config = Config { x_resolution, y_resolution, ..Config::default() };
+            config.set_horizontal_fov(90.0);
+            assert!((config.horizontal_fov() - 90.0).abs() < 0.001);
+            assert!((config.camera_plane_length() - 1.0).abs() < 0.0001);
+        }
+    }
+
+    #[test]
+    fn legends_and_weapons_are_read_from_the_file() {
+        let config: Config = toml::from_str(r#"
+            [item_legend.k]
+            sprite = "/key.png"
+            key = 4
+
+            [[weapons]]
+            sprites = "/weapons/pistol"
+            fire_rate = 5.0
+        "#).unwrap();
+        assert_eq!(config.item_legend[&'k'].key, Some(4));
+        assert_eq!(config.item_legend[&'k'].scale, 1.0);
+        // Listing any weapons replaces the default ones
+        assert_eq!(config.weapons.len(), 1);
+        assert_eq!(config.weapons[0].fire_rate, 5.0);
+        assert_eq!(config.weapons[0].damage, 1);
+    }
+}
@@ -0,0 +1,91 @@
+use ggez::{input::keyboard::KeyCode, GameError};
+use serde::{Deserialize, Serialize};
+
+/// Everything the player did during a single update, read once at its start.
+/// The game only ever looks at this snapshot, so it plays out the same whether it came
+/// from the keyboard, mouse and gamepad or from a recorded demo.
+#[derive(Clone, Default, Serialize, Deserialize)]
+pub struct FrameInput {
+    pub delta: f32, // Seconds since the last update, which decides how many fixed ticks it runs
+    pub held: Vec<KeyCode>,
+    pub pressed: Vec<KeyCode>, // Keys pressed since the last update
+    pub clicked: bool, // Whether the left mouse button was pressed since the last update
+    pub mouse_delta: [f32; 2], // In pixels
+    pub move_stick: [f32; 2], // Left gamepad stick with the deadzone applied, y pointing forward
+    pub turn_stick: f32, // Right gamepad stick x with the deadzone applied, positive turning right
+}
+
+impl FrameInput {
+    /// Checks whether a bound key is held down. Unbound keys never are.
+    pub fn is_held(&self, key: Option<KeyCode>) -> bool {
+        key.is_some_and(|key| self.held.contains(&key))
+    }
+
+    /// Checks whether a bound key was pressed since the last update. Unbound keys never are.
+    pub fn was_pressed(&self, key: Option<KeyCode>) -> bool {
+        key.is_some_and(|key| self.pressed.contains(&key))
+    }
+}
+
+/// A recording of the input for every update of a play session, saved as TOML.
+/// Replaying it from the spawn of the same map, with the same config, plays out identically.
+#[derive(Serialize, Deserialize)]
+struct Demo {
+    map_path: String,
+    frames: Vec<FrameInput>,
+}
+
+/// Records the input of every update, to be saved as a demo once the game quits
+pub struct DemoRecorder {
+    path: String, // Where the demo is saved
+    demo: Demo,
+}
+
+impl DemoRecorder {
+    pub fn new(path: &str, map_path: &str) -> DemoRecorder {
+        let demo = Demo { map_path: String::from(map_path), frames: Vec::new() };
+        DemoRecorder { path: String::from(path), demo }
+    }
+
+    pub fn record(&mut self, input: &FrameInput) {
+        self.demo.frames.push(input.clone());
+    }
+
+    pub fn frame_count(&self) -> usize {
+        self.demo.frames.len()
+    }
+
+    pub fn save(&self) -> Result<(), GameError> {
+        let path = &self.path;
+        let demo_str = toml::to_string(&self.demo)
+            .map_err(|error| GameError::CustomError(format!("Failed serializing demo: {error}")))?;
+        std::fs::write(path, demo_str)
+            .map_err(|error| GameError::CustomError(format!("Failed writing {path}: {error}")))
+    }
+}
+
+/// Feeds the input of a recorded demo back into the game, one update at a time
+pub struct DemoPlayer {
+    frames: std::vec::IntoIter<FrameInput>,
+}
+
+impl DemoPlayer {
+    /// Loads the demo at `path`, refusing it if it was recorded on a map other than `map_path`
+    pub fn load(path: &str, map_path: &str) -> Result<DemoPlayer, GameError> {
+        let demo_str = std::fs::read_to_string(path)
+            .map_err(|error| GameError::CustomError(format!("Failed reading {path}: {error}")))?;
+        let demo: Demo = toml::from_str(&demo_str)
+            .map_err(|error| GameError::CustomError(format!("Failed parsing {path}: {error}")))?;
+        if demo.map_path != map_path {
+            return Err(GameError::CustomError(format!(
+                "Demo {path} was recorded on {}, not {map_path}", demo.map_path
+            )));
+        }
+        Ok(DemoPlayer { frames: demo.frames.into_iter() })
+    }
+
+    /// The input for the next update, or `None` once the demo is over
+    pub fn next_frame(&mut self) -> Option<FrameInput> {
+        self.frames.next()
+    }
+}
@@ -0,0 +1,91 @@
+//! A copy of `ggez::event::run` that also passes on the raw motion of the mouse, which ggez 0.9 has no way to read.
+//! It relies on parts of `Context` that are public but meant for ggez itself (`continuing`, `quit_requested`,
+//! `process_event` and the per-frame bookkeeping in `run_frame`), so ggez is pinned to the exact version in Cargo.toml.
+//! Compare this with `event::run` in the new version before bumping it.
+
+use ggez::{
+    event::{winit_event::{DeviceEvent, ElementState, Event, KeyboardInput, WindowEvent}, ControlFlow, EventHandler, EventLoop},
+    input::keyboard::KeyInput,
+    Context,
+    GameError,
+};
+
+/// An event handler that also takes the raw motion of the mouse
+pub trait RawMouseHandler: EventHandler<GameError> {
+    /// Called for every movement of the mouse itself, in device units.
+    /// Unlike the cursor, the device keeps reporting motion when a grabbed cursor is stuck at the edge of the window.
+    fn raw_mouse_motion_event(&mut self, ctx: &mut Context, dx: f32, dy: f32);
+}
+
+/// Runs the game like `ggez::event::run`, which drops device events, but also passes on the raw mouse motion.
+/// Only the events the game handles are dispatched.
+pub fn run<S: RawMouseHandler + 'static>(mut ctx: Context, event_loop: EventLoop<()>, mut state: S) -> ! {
+    event_loop.run(move |mut event, _, control_flow| {
+        let ctx = &mut ctx;
+        let state = &mut state;
+        if ctx.quit_requested {
+            ctx.quit_requested = false;
+            match state.quit_event(ctx) {
+                Ok(false) => ctx.continuing = false,
+                Ok(true) => (),
+                Err(error) => report(error, control_flow),
+            }
+        }
+        if !ctx.continuing {
+            *control_flow = ControlFlow::Exit;
+            return;
+        }
+        *control_flow = ControlFlow::Poll;
+
+        ggez::event::process_event(ctx, &mut event);
+        let result = match event {
+            Event::DeviceEvent { event: DeviceEvent::MouseMotion { delta: (dx, dy) }, .. } => {
+                state.raw_mouse_motion_event(ctx, dx as f32, dy as f32);
+                Ok(())
+            }
+            Event::WindowEvent { event, .. } => match event {
+                WindowEvent::Resized(size) => state.resize_event(ctx, size.width as f32, size.height as f32),
+                WindowEvent::CloseRequested => state.quit_event(ctx).map(|cancelled| {
+                    if !cancelled {
+                        ctx.continuing = false;
+                    }
+                }),
+                WindowEvent::Focused(gained) => state.focus_event(ctx, gained),
+                WindowEvent::KeyboardInput {
+                    input: KeyboardInput { state: ElementState::Pressed, virtual_keycode: keycode, scancode, .. },
+                    ..
+                } => {
+                    let repeated = ctx.keyboard.is_key_repeated();
+                    let input = KeyInput { scancode, keycode, mods: ctx.keyboard.active_mods() };
+                    state.key_down_event(ctx, input, repeated)
+                }
+                _ => Ok(()),
+            },
+            Event::MainEventsCleared => run_frame(ctx, state),
+            _ => Ok(()),
+        };
+        if let Err(error) = result {
+            report(error, control_flow);
+        }
+    })
+}
+
+/// Updates and draws a single frame, the same way ggez's own event loop does
+fn run_frame<S: RawMouseHandler>(ctx: &mut Context, state: &mut S) -> Result<(), GameError> {
+    ctx.time.tick();
+    // Gamepad state only updates as its events are read
+    while ctx.gamepad.next_event().is_some() {}
+    state.update(ctx)?;
+    ctx.gfx.begin_frame()?;
+    let drawn = state.draw(ctx);
+    ctx.gfx.end_frame()?;
+    ctx.mouse.reset_delta();
+    ctx.keyboard.save_keyboard_state();
+    ctx.mouse.save_mouse_state();
+    drawn
+}
+
+fn report(error: GameError, control_flow: &mut ControlFlow) {
+    eprintln!("{error}");
+    *control_flow = ControlFlow::Exit;
+}
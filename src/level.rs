@@ -0,0 +1,1156 @@
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use crate::parsing::{find_doors, orient_diagonals, parse_map};
+use crate::player::Player;
+use crate::render::{CeilingMode, FloorMode};
+use crate::sprites::{Decoration, Enemy, Item};
+use ggez::glam::{vec2, Vec2};
+
+// Gameplay parameters
+const DOOR_SPEED: f32 = 1.0; // In doors / second
+const SECRET_WALL_SPEED: f32 = 1.0; // In tiles / second
+pub const LIFT_STEP: f32 = 0.1; // How much each step of a lift raises the player, as a fraction of wall height
+
+// Rendering parameters
+const MAX_WALL_LAYERS: usize = 4; // How many walls a ray can see past short and transparent walls, including the last one
+pub const RAY_EPSILON: f32 = 0.0001; // How far past a wall a ray continues from when seeing through it
+const OCCLUSION_DISTANCE: f32 = 0.3; // How far from a wall the floor starts darkening, in units
+const OCCLUSION_STRENGTH: f32 = 0.4; // How much darker the floor is right against a wall, from 0 to 1
+const DECORATION_GRID_MIN: usize = 64; // Levels with fewer decorations than this project every one each frame instead of looking them up by tile
+const DECORATION_CULL_MARGIN: f32 = 1.5; // How far outside the view a decoration's tile can be while it's still projected, in units. Covers the tile and wide sprites
+
+// Misc parameters
+pub const MISSING_TEXTURE: usize = usize::MAX; // Wall texture index that always resolves to the missing texture
+
+#[derive(PartialEq, Eq, Clone, Copy)]
+pub enum Side {
+    NorthSouth,
+    EastWest
+}
+
+/// One of the four faces of a tile, named after the direction it faces. North is towards negative y.
+#[derive(Clone, Copy)]
+pub enum Face {
+    North,
+    East,
+    South,
+    West,
+}
+
+impl Face {
+    /// The face a ray travelling along `direction` hits when it crosses into a tile on `side`
+    fn hit_by(side: Side, direction: Vec2) -> Face {
+        match (side, direction.x > 0.0, direction.y > 0.0) {
+            (Side::EastWest, true, _) => Face::West,
+            (Side::EastWest, false, _) => Face::East,
+            (Side::NorthSouth, _, true) => Face::North,
+            (Side::NorthSouth, _, false) => Face::South,
+        }
+    }
+}
+
+/// Which corner of its cell a diagonal wall fills, cutting the cell in half from corner to corner
+#[derive(Clone, Copy)]
+pub enum Diagonal {
+    NorthWest,
+    NorthEast,
+    SouthEast,
+    SouthWest,
+}
+
+impl Diagonal {
+    /// The line the wall's face lies on, in coordinates within the cell from 0 to 1,
+    /// as a normal pointing out of the wall and the offset of the line along it
+    fn face(self) -> (Vec2, f32) {
+        match self {
+            Diagonal::NorthWest => (vec2(1.0, 1.0), 1.0),
+            Diagonal::NorthEast => (vec2(-1.0, 1.0), 0.0),
+            Diagonal::SouthEast => (vec2(-1.0, -1.0), -1.0),
+            Diagonal::SouthWest => (vec2(1.0, -1.0), 0.0),
+        }
+    }
+
+    /// Checks whether a point, in coordinates within the cell from 0 to 1, is inside the wall
+    fn covers(self, point: Vec2) -> bool {
+        let (normal, offset) = self.face();
+        normal.dot(point) < offset
+    }
+
+    /// The diagonal filling the opposite corner, along the same line
+    pub fn opposite(self) -> Diagonal {
+        match self {
+            Diagonal::NorthWest => Diagonal::SouthEast,
+            Diagonal::NorthEast => Diagonal::SouthWest,
+            Diagonal::SouthEast => Diagonal::NorthWest,
+            Diagonal::SouthWest => Diagonal::NorthEast,
+        }
+    }
+
+    /// The side the face is shaded like, so the two slopes are told apart the way the two axes are
+    fn shading_side(self) -> Side {
+        match self {
+            Diagonal::NorthWest | Diagonal::SouthEast => Side::EastWest,
+            Diagonal::NorthEast | Diagonal::SouthWest => Side::NorthSouth,
+        }
+    }
+}
+
+/// A solid tile in the map
+#[derive(Clone, Copy)]
+pub struct Tile {
+    pub textures: [usize; 4], // One per face, indexed by `Face`. The face of a diagonal wall uses the north texture
+    thin: bool, // Thin walls are drawn recessed halfway into their cell
+    pub diagonal: Option<Diagonal>, // Diagonal walls only fill half their cell, the rest is open floor
+    height: f32, // Relative to a regular wall. Walls of any height block movement, so they can't be climbed
+    transparent: bool, // Transparent walls, like bars or windows, are drawn over whatever is behind them
+}
+
+impl Tile {
+    pub fn wall(texture: usize) -> Tile {
+        Tile {
+            textures: [texture; 4],
+            thin: false,
+            diagonal: None,
+            height: 1.0,
+            transparent: false,
+        }
+    }
+
+    /// Cuts the wall down to the half of its cell in the `diagonal` corner
+    pub fn diagonal(self, diagonal: Diagonal) -> Tile {
+        Tile {
+            diagonal: Some(diagonal),
+            ..self
+        }
+    }
+
+    pub fn thin_wall(texture: usize) -> Tile {
+        Tile {
+            thin: true,
+            ..Tile::wall(texture)
+        }
+    }
+
+    pub fn with_height(self, height: f32) -> Tile {
+        Tile {
+            height,
+            ..self
+        }
+    }
+
+    /// Gives each face its own texture, in the order of `Face`
+    pub fn with_face_textures(self, textures: [usize; 4]) -> Tile {
+        Tile {
+            textures,
+            ..self
+        }
+    }
+
+    pub fn texture(&self, face: Face) -> usize {
+        self.textures[face as usize]
+    }
+
+    /// Lets rays continue past the wall, so the transparent parts of its texture show what's behind it
+    pub fn transparent(self) -> Tile {
+        Tile {
+            transparent: true,
+            ..self
+        }
+    }
+}
+
+/// The result of casting a single ray into the map
+#[derive(PartialEq)]
+pub struct RayHit {
+    pub perpendicular_distance: f32,
+    pub side: Side,
+    pub texture_index: usize,
+    pub wall_x: f32, // Where along the wall the ray hit, from 0 to 1
+    pub height: f32, // Height of the wall that was hit
+    pub transparent: bool, // Whether the wall that was hit can be seen through
+    pub tile: (i32, i32), // Map coordinates of the wall that was hit
+    pub diagonal: bool, // Whether the ray hit the face of a diagonal wall, which textures run across the same way from every angle
+}
+
+/// A door that slides sideways into the wall when opened
+pub struct Door {
+    pub x: i32,
+    pub y: i32,
+    open_amount: f32, // 0 is fully closed, 1 is fully open
+    pub opening: bool,
+    pub key_id: Option<u32>, // The key needed to unlock the door, if it's locked
+}
+
+impl Door {
+    pub fn new(x: i32, y: i32, key_id: Option<u32>) -> Door {
+        Door {
+            x,
+            y,
+            open_amount: 0.0,
+            opening: false,
+            key_id,
+        }
+    }
+
+    fn is_open(&self) -> bool {
+        self.open_amount >= 1.0
+    }
+
+    /// Unlocks the door if `keys` holds the key to it, using the key up.
+    /// Returns whether the door is unlocked, which unlocked doors always are
+    pub fn try_unlock(&mut self, keys: &mut Vec<u32>) -> bool {
+        let Some(key_id) = self.key_id else {
+            return true;
+        };
+        let Some(key_index) = keys.iter().position(|key| *key == key_id) else {
+            return false;
+        };
+        keys.remove(key_index);
+        self.key_id = None;
+        true
+    }
+
+    /// Slides the door towards being open or closed
+    pub fn advance(&mut self, delta: f32) {
+        let direction = match self.opening {
+            true => 1.0,
+            false => -1.0,
+        };
+        self.open_amount = (self.open_amount + direction * DOOR_SPEED * delta).clamp(0.0, 1.0);
+    }
+}
+
+/// Brightens the walls, floors and ceilings around it, fading out linearly to nothing at `radius`
+pub struct Light {
+    pub position: Vec2,
+    pub color: [f32; 3],
+    pub radius: f32, // In units
+}
+
+/// Moves the player from one tile to another when stepped on.
+/// Teleporters come in pairs, one going each way.
+pub struct Teleporter {
+    pub source: (i32, i32),
+    pub destination: (i32, i32),
+}
+
+/// Something scripted that happens in a level, set off by a trigger
+#[derive(Clone)]
+pub enum Event {
+    Message(String), // Shows the text in the middle of the screen
+    OpenDoor { x: i32, y: i32 }, // Opens the door on that tile, unlocking it for good if it's locked
+    SpawnEnemy { x: i32, y: i32 }, // Places a new enemy in the middle of that tile
+}
+
+/// An invisible tile that sets off an event the first time the player steps on it
+pub struct Trigger {
+    pub x: i32,
+    pub y: i32,
+    pub event: Event,
+    pub fired: bool,
+}
+
+/// A wall that slides one tile back when used, revealing whatever is behind it.
+/// While sliding it's drawn and collided with on its own, and once fully pushed it's put back in the map
+/// on the tile it slid onto, leaving its original tile open.
+pub struct SecretWall {
+    pub x: i32,
+    pub y: i32,
+    pub direction: (i32, i32), // Which way the wall slides, one tile along an axis
+    pub tile: Tile,
+    pub offset: f32, // How far the wall has slid, from 0 to 1 once it's fully pushed
+    pub pushing: bool,
+}
+
+impl SecretWall {
+    fn is_moving(&self) -> bool {
+        self.pushing && self.offset < 1.0
+    }
+
+    fn destination(&self) -> (i32, i32) {
+        (self.x + self.direction.0, self.y + self.direction.1)
+    }
+
+    /// Checks whether the wall is on the given tile or sliding onto it
+    fn covers(&self, x: i32, y: i32) -> bool {
+        (x, y) == (self.x, self.y) || (x, y) == self.destination()
+    }
+
+    /// The smallest corner of the square the wall takes up while sliding
+    fn corner(&self) -> Vec2 {
+        let (dx, dy) = self.direction;
+        vec2(self.x as f32, self.y as f32) + vec2(dx as f32, dy as f32) * self.offset
+    }
+
+    /// Intersects a ray with the sliding wall, treating it as a unit square.
+    /// Only hits no farther than `max_distance` along the ray count.
+    fn hit_by(&self, origin: Vec2, direction: Vec2, max_distance: f32) -> Option<RayHit> {
+        let min = self.corner();
+        let max = min + Vec2::ONE;
+        // Where the ray enters and leaves the wall's span along one axis
+        let slab = |origin: f32, direction: f32, min: f32, max: f32| match direction == 0.0 {
+            true => match min <= origin && origin <= max {
+                true => (f32::NEG_INFINITY, f32::INFINITY),
+                false => (f32::INFINITY, f32::NEG_INFINITY),
+            },
+            false => {
+                let (a, b) = ((min - origin) / direction, (max - origin) / direction);
+                (a.min(b), a.max(b))
+            }
+        };
+        let (x_enter, x_exit) = slab(origin.x, direction.x, min.x, max.x);
+        let (y_enter, y_exit) = slab(origin.y, direction.y, min.y, max.y);
+        let (perpendicular_distance, side) = match x_enter > y_enter {
+            true => (x_enter, Side::EastWest),
+            false => (y_enter, Side::NorthSouth),
+        };
+        if perpendicular_distance < 0.0 || perpendicular_distance > x_exit.min(y_exit) || perpendicular_distance > max_distance {
+            return None;
+        }
+        let hit_point = origin + direction * perpendicular_distance;
+        let wall_x = match side {
+            Side::EastWest => hit_point.y - min.y,
+            Side::NorthSouth => hit_point.x - min.x,
+        };
+        Some(RayHit {
+            perpendicular_distance,
+            side,
+            texture_index: self.tile.texture(Face::hit_by(side, direction)),
+            wall_x: wall_x.clamp(0.0, 1.0),
+            height: self.tile.height,
+            transparent: self.tile.transparent,
+            tile: (self.x, self.y),
+            diagonal: false,
+        })
+    }
+}
+
+/// A problem with a level that makes it unplayable
+pub enum MapError {
+    /// The spawn is inside a wall
+    SpawnInWall { x: i32, y: i32 },
+    /// Open floor reachable from the spawn touches the edge of the map
+    Leak { x: i32, y: i32 },
+    /// One end of a teleporter is inside a wall, so arriving there would trap the player
+    TeleporterInWall { x: i32, y: i32 },
+}
+
+impl fmt::Display for MapError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            MapError::SpawnInWall { x, y } => write!(f, "the spawn at ({x}, {y}) is inside a wall"),
+            MapError::Leak { x, y } => write!(f, "the level isn't enclosed, open floor at ({x}, {y}) reaches the edge of the map"),
+            MapError::TeleporterInWall { x, y } => write!(f, "the teleporter at ({x}, {y}) is inside a wall"),
+        }
+    }
+}
+
+pub struct Level {
+    pub map: Vec<Vec<Option<Tile>>>,
+    pub doors: Vec<Door>,
+    pub floor_map: Vec<Vec<usize>>, // Floor texture per tile, may be empty
+    pub ceiling_map: Vec<Vec<usize>>, // Ceiling texture per tile, may be empty
+    pub decorations: Vec<Decoration>,
+    pub decoration_grid: Vec<Vec<Vec<usize>>>, // Indices into `decorations` of the ones standing on each tile, empty below `DECORATION_GRID_MIN`
+    pub items: Vec<Item>,
+    pub enemies: Vec<Enemy>,
+    pub teleporters: Vec<Teleporter>,
+    pub triggers: Vec<Trigger>,
+    pub lights: Vec<Light>,
+    pub secret_walls: Vec<SecretWall>,
+    pub light_map: Vec<Vec<f32>>, // Brightness per tile from 0 to 1, may be empty
+    pub lift_map: Vec<Vec<usize>>, // How many `LIFT_STEP`s standing on each tile raises the player, may be empty
+    pub sky_map: Vec<Vec<bool>>, // Whether each tile is open to the sky instead of having a ceiling, may be empty
+    pub liquid_map: Vec<Vec<Option<char>>>, // The liquid on each tile's floor, keyed into the config's `liquids`, may be empty
+    pub floor_mode: FloorMode,
+    pub ceiling_mode: CeilingMode,
+}
+
+impl Level {
+    /// A level of just the walls and doors of a tile grid, with every other layer left empty
+    pub fn from_tiles(tiles_str: &str, legend: &HashMap<char, Option<Tile>>) -> Level {
+        let mut map = parse_map(tiles_str, legend);
+        orient_diagonals(&mut map);
+        Level {
+            map,
+            doors: find_doors(tiles_str, &[]),
+            floor_map: Vec::new(),
+            ceiling_map: Vec::new(),
+            decorations: Vec::new(),
+            decoration_grid: Vec::new(),
+            items: Vec::new(),
+            enemies: Vec::new(),
+            teleporters: Vec::new(),
+            triggers: Vec::new(),
+            lights: Vec::new(),
+            secret_walls: Vec::new(),
+            light_map: Vec::new(),
+            lift_map: Vec::new(),
+            sky_map: Vec::new(),
+            liquid_map: Vec::new(),
+            floor_mode: FloorMode::Tiled,
+            ceiling_mode: CeilingMode::Tiled,
+        }
+    }
+
+    /// Returns the tile at the given map coordinates, see [`tile_at`]
+    pub fn tile_at(&self, x: i32, y: i32) -> Option<Tile> {
+        tile_at(&self.map, x, y)
+    }
+
+    fn door_at(&self, x: i32, y: i32) -> Option<&Door> {
+        self.doors.iter().find(|door| door.x == x && door.y == y)
+    }
+
+    /// Marks the triggers on the given tile that haven't fired yet as fired, returning their events
+    pub fn fire_triggers(&mut self, x: i32, y: i32) -> Vec<Event> {
+        self.triggers
+            .iter_mut()
+            .filter(|trigger| !trigger.fired && (trigger.x, trigger.y) == (x, y))
+            .map(|trigger| {
+                trigger.fired = true;
+                trigger.event.clone()
+            })
+            .collect::<Vec<Event>>()
+    }
+
+    /// The painted brightness of a tile, full brightness outside of the light map
+    pub fn light_map_at(&self, x: i32, y: i32) -> f32 {
+        if x < 0 || y < 0 {
+            return 1.0;
+        }
+        self.light_map.get(y as usize).and_then(|row| row.get(x as usize)).copied().unwrap_or(1.0)
+    }
+
+    /// The decorations that can be in view of the player, within `render_distance` and the view cone.
+    /// Levels with a decoration grid only look at the tiles around the player, so far-off decorations
+    /// are skipped without being projected. Smaller levels just return every decoration.
+    pub fn decorations_in_view(&self, player: &Player, render_distance: f32) -> Vec<&Decoration> {
+        match self.decoration_grid.is_empty() {
+            true => self.decorations.iter().collect(),
+            false => grid_in_view(&self.decoration_grid, player, render_distance)
+                .into_iter()
+                .map(|index| &self.decorations[index])
+                .collect(),
+        }
+    }
+
+    /// Whether a tile has a ceiling over it. Every tile does unless the sky layer opens it up
+    pub fn has_ceiling(&self, x: i32, y: i32) -> bool {
+        if x < 0 || y < 0 {
+            return true;
+        }
+        !self.sky_map.get(y as usize).and_then(|row| row.get(x as usize)).copied().unwrap_or(false)
+    }
+
+    /// The map character of the liquid on the floor of a tile, if it has any
+    pub fn liquid_at(&self, x: i32, y: i32) -> Option<char> {
+        if x < 0 || y < 0 {
+            return None;
+        }
+        self.liquid_map.get(y as usize).and_then(|row| row.get(x as usize)).copied().flatten()
+    }
+
+    /// The light from every light source falling on a point, per color channel
+    pub fn light_at(&self, point: Vec2) -> [f32; 3] {
+        self.lights.iter().fold([0.0; 3], |total, light| {
+            let falloff = (1.0 - point.distance(light.position) / light.radius).max(0.0);
+            [0, 1, 2].map(|channel| total[channel] + light.color[channel] * falloff)
+        })
+    }
+
+    /// How much the floor at `fraction` within the cell at the given map coordinates is darkened
+    /// by the walls around it, as a factor to multiply its color with. Edges bordering a full wall darken,
+    /// and corners between two walls darken the most.
+    pub fn occlusion_at(&self, x: i32, y: i32, fraction: Vec2) -> f32 {
+        let occludes = |x: i32, y: i32| self.tile_at(x, y).is_some_and(|tile| !tile.thin && tile.diagonal.is_none());
+        let edges = [
+            (occludes(x - 1, y), fraction.x),
+            (occludes(x + 1, y), 1.0 - fraction.x),
+            (occludes(x, y - 1), fraction.y),
+            (occludes(x, y + 1), 1.0 - fraction.y),
+        ];
+        edges.iter()
+            .filter(|(occluded, _)| *occluded)
+            .fold(1.0, |factor, (_, distance)| {
+                let closeness = (1.0 - distance / OCCLUSION_DISTANCE).max(0.0);
+                factor * (1.0 - OCCLUSION_STRENGTH * closeness)
+            })
+    }
+
+    pub fn teleporter_at(&self, x: i32, y: i32) -> Option<&Teleporter> {
+        self.teleporters.iter().find(|teleporter| teleporter.source == (x, y))
+    }
+
+    /// Checks whether the tile at the given map coordinates blocks movement
+    pub fn is_solid(&self, x: i32, y: i32) -> bool {
+        // A sliding secret wall blocks both tiles it's between
+        if self.secret_walls.iter().any(|wall| wall.is_moving() && wall.covers(x, y)) {
+            return true;
+        }
+        match self.door_at(x, y) {
+            Some(door) => !door.is_open(),
+            None => self.tile_at(x, y).is_some(),
+        }
+    }
+
+    /// Starts sliding the secret wall on the given tile, if there is one that hasn't been pushed yet.
+    /// Returns whether a wall started sliding.
+    pub fn push_secret_wall(&mut self, x: i32, y: i32) -> bool {
+        let Some(wall) = self.secret_walls.iter_mut().find(|wall| (wall.x, wall.y) == (x, y) && !wall.pushing) else {
+            return false;
+        };
+        wall.pushing = true;
+        // The sliding wall is drawn on its own until it comes to rest
+        self.map[y as usize][x as usize] = None;
+        true
+    }
+
+    /// Slides the secret walls that are being pushed, putting them back in the map once they're done
+    pub fn advance_secret_walls(&mut self, delta: f32) {
+        for wall in &mut self.secret_walls {
+            if !wall.is_moving() {
+                continue;
+            }
+            wall.offset = (wall.offset + SECRET_WALL_SPEED * delta).min(1.0);
+            if !wall.is_moving() {
+                let (x, y) = wall.destination();
+                self.map[y as usize][x as usize] = Some(wall.tile);
+            }
+        }
+    }
+
+    /// Checks that the area reachable from `spawn` is fully enclosed by walls, and that no teleporter is inside one.
+    /// Doors count as open, since the player can walk through them.
+    pub fn validate(&self, spawn: Vec2) -> Result<(), MapError> {
+        // Every teleporter has a partner going the other way, so checking each source covers both ends
+        if let Some(teleporter) = self.teleporters.iter().find(|teleporter| self.is_solid(teleporter.source.0, teleporter.source.1)) {
+            let (x, y) = teleporter.source;
+            return Err(MapError::TeleporterInWall { x, y });
+        }
+        let start = (spawn.x.floor() as i32, spawn.y.floor() as i32);
+        let passable = |(x, y): (i32, i32)| self.tile_at(x, y).is_none() || self.door_at(x, y).is_some();
+        if !passable(start) {
+            return Err(MapError::SpawnInWall { x: start.0, y: start.1 });
+        }
+        let height = self.map.len() as i32;
+        let mut visited = HashSet::from([start]);
+        let mut frontier = vec![start];
+        while let Some((x, y)) = frontier.pop() {
+            let width = self.map[y as usize].len() as i32;
+            if x == 0 || y == 0 || x == width - 1 || y == height - 1 {
+                return Err(MapError::Leak { x, y });
+            }
+            // Teleporters lead to floor that may not be connected any other way
+            let teleport_destination = self.teleporter_at(x, y).map(|teleporter| teleporter.destination);
+            for neighbor in [(x + 1, y), (x - 1, y), (x, y + 1), (x, y - 1)].into_iter().chain(teleport_destination) {
+                if passable(neighbor) && visited.insert(neighbor) {
+                    frontier.push(neighbor);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Warns about every texture index used by the map that has no texture loaded for it,
+    /// along with where the first tile using it is
+    pub fn report_missing_textures(&self, texture_count: usize) {
+        let mut reported = HashSet::new();
+        for (y, row) in self.map.iter().enumerate() {
+            for (x, tile) in row.iter().enumerate() {
+                let Some(tile) = tile else { continue };
+                for texture in tile.textures {
+                    // Unknown map characters are already reported while parsing
+                    if texture < texture_count || texture == MISSING_TEXTURE || !reported.insert(texture) {
+                        continue;
+                    }
+                    eprintln!("Warning: tile at ({x}, {y}) uses wall texture {texture}, but only {texture_count} are loaded");
+                }
+            }
+        }
+    }
+
+    /// Checks whether a square body of the given radius centered on `position` overlaps a wall
+    pub fn collides(&self, position: Vec2, radius: f32) -> bool {
+        // The body is never larger than a tile, so checking its corners is enough
+        [vec2(-radius, -radius), vec2(radius, -radius), vec2(-radius, radius), vec2(radius, radius)]
+            .iter()
+            .map(|corner| position + *corner)
+            .any(|corner| self.is_solid_at(corner))
+    }
+
+    /// Checks whether a point is inside something that blocks movement,
+    /// which for diagonal walls is only the half of the tile they fill
+    fn is_solid_at(&self, point: Vec2) -> bool {
+        let (x, y) = (point.x.floor() as i32, point.y.floor() as i32);
+        if let Some(diagonal) = self.tile_at(x, y).and_then(|tile| tile.diagonal) {
+            return diagonal.covers(point - vec2(x as f32, y as f32));
+        }
+        self.is_solid(x, y)
+    }
+}
+
+/// Intersects a ray with the face of the diagonal wall in the cell at `(x, y)`, as long as it reaches the face
+/// before `exit_distance`, where it leaves the cell. Rays can only hit the face from the open side.
+fn diagonal_hit(tile: Tile, diagonal: Diagonal, (x, y): (i32, i32), origin: Vec2, direction: Vec2, exit_distance: f32) -> Option<RayHit> {
+    let cell = vec2(x as f32, y as f32);
+    let (normal, offset) = diagonal.face();
+    let approach = normal.dot(direction);
+    if approach >= 0.0 {
+        return None;
+    }
+    let perpendicular_distance = (offset - normal.dot(origin - cell)) / approach;
+    if perpendicular_distance < 0.0 || perpendicular_distance > exit_distance {
+        return None;
+    }
+    let hit_point = origin + direction * perpendicular_distance - cell;
+    Some(RayHit {
+        perpendicular_distance,
+        side: diagonal.shading_side(),
+        texture_index: tile.texture(Face::North),
+        // The face runs across the whole width of the cell, so its x works as the texture coordinate
+        wall_x: hit_point.x.clamp(0.0, 1.0),
+        height: tile.height,
+        transparent: tile.transparent,
+        tile: (x, y),
+        diagonal: true,
+    })
+}
+
+/// Returns the tile at the given map coordinates.
+/// Anything outside the map counts as a solid wall with a missing texture,
+/// so rays and movement can never leave the grid.
+pub fn tile_at(map: &[Vec<Option<Tile>>], x: i32, y: i32) -> Option<Tile> {
+    if x < 0 || y < 0 {
+        return Some(Tile::wall(MISSING_TEXTURE));
+    }
+    match map.get(y as usize).and_then(|row| row.get(x as usize)) {
+        Some(tile) => *tile,
+        None => Some(Tile::wall(MISSING_TEXTURE)),
+    }
+}
+
+/// Casts a ray like [`cast_ray`], but keeps going past walls too short to block the view and transparent walls.
+/// Returns the hits from nearest to farthest, ending with the wall that stopped the ray,
+/// or with the last wall before `max_distance` if nothing stopped it.
+pub fn cast_ray_through(map: &[Vec<Option<Tile>>], doors: &[Door], secret_walls: &[SecretWall], origin: Vec2, direction: Vec2, max_distance: f32) -> Vec<RayHit> {
+    let mut hits = Vec::new();
+    let mut travelled = 0.0;
+    loop {
+        // Continue from just past the previous hit so the same wall isn't hit again
+        let Some(mut hit) = cast_ray(map, doors, secret_walls, origin + direction * travelled, direction, max_distance - travelled) else {
+            return hits;
+        };
+        hit.perpendicular_distance += travelled;
+        travelled = hit.perpendicular_distance + RAY_EPSILON;
+        let see_through = (hit.height < 1.0 || hit.transparent) && hits.len() + 1 < MAX_WALL_LAYERS;
+        hits.push(hit);
+        if !see_through {
+            return hits;
+        }
+    }
+}
+
+/// Checks whether a straight line from `from` to `to` is free of walls, other than transparent ones like grates
+pub fn has_line_of_sight(level: &Level, from: Vec2, to: Vec2) -> bool {
+    let offset = to - from;
+    let (direction, distance) = (offset.normalize_or_zero(), offset.length());
+    let mut travelled = 0.0;
+    // With a unit direction the distance to a hit is measured along the line itself
+    while let Some(hit) = cast_ray(&level.map, &level.doors, &level.secret_walls, from + direction * travelled, direction, distance - travelled) {
+        if !hit.transparent {
+            return false;
+        }
+        // Continue from just past the hit so the same wall isn't hit again
+        travelled += hit.perpendicular_distance + RAY_EPSILON;
+    }
+    true
+}
+
+/// Returns the texture index at the given coordinates of a floor or ceiling layer,
+/// falling back to the default texture outside of it
+pub fn layer_at(layer: &[Vec<usize>], x: i32, y: i32) -> usize {
+    if x < 0 || y < 0 {
+        return 0;
+    }
+    layer.get(y as usize).and_then(|row| row.get(x as usize)).copied().unwrap_or(0)
+}
+
+/// Casts a ray from `origin` along `direction` until it hits a wall.
+/// Returns `None` if there's no wall within `max_distance`.
+/// Algorithm courtesy of Lode's Computer Graphics Tutorial
+/// https://lodev.org/cgtutor/raycasting.html
+pub fn cast_ray(map: &[Vec<Option<Tile>>], doors: &[Door], secret_walls: &[SecretWall], origin: Vec2, direction: Vec2, max_distance: f32) -> Option<RayHit> {
+    // Set up DDA
+    let mut map_x = origin.x as i32;
+    let mut map_y = origin.y as i32;
+    let delta_x = match direction.x == 0.0 {true => 99999999.9, false => (1.0 / direction.x).abs()};
+    let delta_y = match direction.y == 0.0 {true => 99999999.9, false => (1.0 / direction.y).abs()};
+    let (x_step, mut x_distance) = match direction.x < 0.0 {
+        true => (-1, (origin.x - map_x as f32) * delta_x),
+        false => (1, (map_x as f32 + 1.0 - origin.x) * delta_x)
+    };
+    let (y_step, mut y_distance) = match direction.y < 0.0 {
+        true => (-1, (origin.y - map_y as f32) * delta_y),
+        false => (1, (map_y as f32 + 1.0 - origin.y) * delta_y)
+    };
+    // The ray never steps into the cell it starts in, so a diagonal wall there is checked up front
+    if let Some(tile) = tile_at(map, map_x, map_y) {
+        if let Some(diagonal) = tile.diagonal {
+            let hit = diagonal_hit(tile, diagonal, (map_x, map_y), origin, direction, x_distance.min(y_distance));
+            if hit.as_ref().is_some_and(|hit| hit.perpendicular_distance <= max_distance) {
+                return hit;
+            }
+        }
+    }
+    // Execute DDA
+    loop {
+        let side = if x_distance < y_distance {
+            x_distance += delta_x;
+            map_x += x_step;
+            Side::EastWest
+        }
+        else {
+            y_distance += delta_y;
+            map_y += y_step;
+            Side::NorthSouth
+        };
+        let mut perpendicular_distance = match side {
+            Side::EastWest => x_distance - delta_x,
+            Side::NorthSouth => y_distance - delta_y
+        };
+        if perpendicular_distance > max_distance {
+            return None;
+        }
+        // Sliding secret walls sit between tiles, so they're intersected directly
+        if let Some(wall) = secret_walls.iter().find(|wall| wall.is_moving() && wall.covers(map_x, map_y)) {
+            if let Some(hit) = wall.hit_by(origin, direction, max_distance) {
+                return Some(hit);
+            }
+            continue;
+        }
+        let Some(tile) = tile_at(map, map_x, map_y) else {
+            continue;
+        };
+        if let Some(diagonal) = tile.diagonal {
+            // Rays entering through the filled half hit the edge of the cell like any other wall,
+            // the rest can only hit the diagonal face before leaving the cell again
+            let entry = origin + direction * perpendicular_distance - vec2(map_x as f32, map_y as f32);
+            if !diagonal.covers(entry) {
+                match diagonal_hit(tile, diagonal, (map_x, map_y), origin, direction, x_distance.min(y_distance)) {
+                    Some(hit) if hit.perpendicular_distance <= max_distance => return Some(hit),
+                    Some(_) => return None,
+                    None => continue,
+                }
+            }
+        }
+        if tile.thin {
+            // Move the hit to a plane halfway into the cell, parallel to the face the ray entered through.
+            // If the ray leaves the cell through one of its other faces before reaching it, it missed.
+            let (recessed_distance, exit_distance) = match side {
+                Side::EastWest => (perpendicular_distance + delta_x / 2.0, y_distance),
+                Side::NorthSouth => (perpendicular_distance + delta_y / 2.0, x_distance),
+            };
+            if recessed_distance > exit_distance {
+                continue;
+            }
+            perpendicular_distance = recessed_distance;
+        }
+        let wall_x = match side {
+            Side::EastWest => origin.y + perpendicular_distance * direction.y,
+            Side::NorthSouth => origin.x + perpendicular_distance * direction.x,
+        };
+        let mut wall_x = wall_x - wall_x.floor();
+        if let Some(door) = doors.iter().find(|door| door.x == map_x && door.y == map_y) {
+            // Let the ray through the part of the doorway the door has slid out of
+            if wall_x < door.open_amount {
+                continue;
+            }
+            wall_x -= door.open_amount;
+        }
+        return Some(RayHit {
+            perpendicular_distance,
+            side,
+            texture_index: tile.texture(Face::hit_by(side, direction)),
+            wall_x,
+            height: tile.height,
+            transparent: tile.transparent,
+            tile: (map_x, map_y),
+            diagonal: false,
+        });
+    }
+}
+
+/// The indices in every tile of `grid` within `render_distance` of the player and inside their view cone,
+/// widened by `DECORATION_CULL_MARGIN` so sprites standing just outside it still show
+pub fn grid_in_view(grid: &[Vec<Vec<usize>>], player: &Player, render_distance: f32) -> Vec<usize> {
+    let width = grid.first().map_or(0, |row| row.len()) as i32;
+    let height = grid.len() as i32;
+    let reach = render_distance + DECORATION_CULL_MARGIN;
+    let (min, max) = ((player.position - reach).floor(), (player.position + reach).floor());
+    let forward = player.direction.normalize();
+    let right = player.camera.normalize();
+    let half_width = player.fov() / player.direction.length(); // Tangent of half the horizontal field of view
+    // How far a point can be past either edge of the view cone while within the margin of it, measured sideways
+    let slack = DECORATION_CULL_MARGIN * (1.0 + half_width * half_width).sqrt();
+    let mut visible = Vec::new();
+    for y in (min.y as i32).max(0)..=(max.y as i32).min(height - 1) {
+        for x in (min.x as i32).max(0)..=(max.x as i32).min(width - 1) {
+            let offset = vec2(x as f32 + 0.5, y as f32 + 0.5) - player.position;
+            let depth = offset.dot(forward);
+            let in_cone = depth > -DECORATION_CULL_MARGIN && offset.dot(right).abs() - depth * half_width <= slack;
+            if in_cone && offset.length() <= reach {
+                visible.extend_from_slice(&grid[y as usize][x as usize]);
+            }
+        }
+    }
+    visible
+}
+
+/// Sorts decorations into the map tiles they stand on by their `positions`, see [`Level::decorations_in_view`].
+/// Built when the level is loaded, which is the only time decorations are added or removed.
+/// Returns an empty grid for levels with fewer than `DECORATION_GRID_MIN` decorations, which are quick enough to go through one by one.
+pub fn decoration_grid(positions: impl ExactSizeIterator<Item = Vec2>, map: &[Vec<Option<Tile>>]) -> Vec<Vec<Vec<usize>>> {
+    let width = map.first().map_or(0, |row| row.len());
+    if positions.len() < DECORATION_GRID_MIN || width == 0 {
+        return Vec::new();
+    }
+    let mut grid = vec![vec![Vec::new(); width]; map.len()];
+    for (index, position) in positions.enumerate() {
+        // Decorations placed past the edge of the map go in the nearest tile on it
+        let x = (position.x.floor().max(0.0) as usize).min(width - 1);
+        let y = (position.y.floor().max(0.0) as usize).min(map.len() - 1);
+        grid[y][x].push(index);
+    }
+    grid
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use crate::config::Config;
+    use crate::parsing::{find_secret_walls, find_teleporters, find_triggers, parse_events, tile_legend};
+    use crate::player::{Player, COLLISION_RADIUS};
+
+    /// Builds a level out of the rows of a tile grid, with nothing else in it
+    pub(crate) fn level(rows: &[&str]) -> Level {
+        Level::from_tiles(&rows.join("\n"), &tile_legend(&Config::default()))
+    }
+
+    /// Casts a ray with no limit on how far it goes
+    pub(crate) fn cast(level: &Level, origin: Vec2, direction: Vec2) -> Option<RayHit> {
+        cast_ray(&level.map, &level.doors, &level.secret_walls, origin, direction, f32::INFINITY)
+    }
+
+    #[test]
+    fn outside_the_map_is_solid() {
+        let level = level(&["..", ".."]);
+        for (x, y) in [(-1, 0), (0, -1), (2, 0), (0, 2), (i32::MAX, i32::MIN)] {
+            assert_eq!(level.tile_at(x, y).map(|tile| tile.textures), Some([usize::MAX; 4]), "({x}, {y}) should be solid");
+        }
+        assert!(level.tile_at(1, 1).is_none());
+    }
+
+    #[test]
+    fn rays_hit_the_walls_of_a_room() {
+        let level = level(&["SSS", "S.S", "SSS"]);
+        let east = cast(&level, vec2(1.5, 1.5), vec2(1.0, 0.0)).expect("ray should hit a wall");
+        assert_eq!(east.perpendicular_distance, 0.5);
+        assert!(east.side == Side::EastWest);
+        assert_eq!(east.wall_x, 0.5);
+        let north = cast(&level, vec2(1.5, 1.25), vec2(0.0, -1.0)).expect("ray should hit a wall");
+        assert_eq!(north.perpendicular_distance, 0.25);
+        assert!(north.side == Side::NorthSouth);
+        assert_eq!(north.texture_index, 0);
+    }
+
+    #[test]
+    fn rays_measure_perpendicular_distance() {
+        let level = level(&["SSSS", "S..S", "S..S", "SSSS"]);
+        // Rays off to the side of the view are longer than the view direction. Distances are measured in multiples
+        // of the ray, which is the distance along the view direction, so walls don't bulge towards the middle
+        let hit = cast(&level, vec2(1.5, 2.5), vec2(0.5, -1.0)).expect("ray should hit a wall");
+        assert_eq!(hit.perpendicular_distance, 1.5);
+        assert_eq!(hit.wall_x, 0.25);
+    }
+
+    #[test]
+    fn rays_hit_thin_walls_halfway_into_their_cell() {
+        let level = level(&["SSSSS", "S.D.S", "SSSSS"]);
+        let hit = cast(&level, vec2(1.5, 1.5), vec2(1.0, 0.0)).expect("ray should hit the door");
+        assert_eq!(hit.perpendicular_distance, 1.0);
+        assert_eq!(hit.tile, (2, 1));
+        assert_eq!(hit.texture_index, 2);
+        // A ray leaving the cell sideways before reaching the middle misses it
+        let hit = cast(&level, vec2(1.9, 1.9), vec2(1.0, -2.0)).expect("ray should hit the wall above the door");
+        assert_eq!(hit.tile, (2, 0));
+        assert!(hit.side == Side::NorthSouth);
+    }
+
+    #[test]
+    fn rays_pass_through_open_doors() {
+        let mut level = level(&["SSSSS", "S.D.S", "SSSSS"]);
+        level.doors[0].open_amount = 1.0;
+        let hit = cast(&level, vec2(1.5, 1.5), vec2(1.0, 0.0)).expect("ray should hit the far wall");
+        assert_eq!(hit.tile, (4, 1));
+        assert_eq!(hit.perpendicular_distance, 2.5);
+    }
+
+    #[test]
+    fn rays_stop_at_the_edge_of_an_open_map() {
+        let level = level(&["...", "...", "..."]);
+        for step in 0..16 {
+            let direction = Vec2::from_angle(step as f32 * std::f32::consts::TAU / 16.0);
+            let hit = cast(&level, vec2(1.5, 1.5), direction).expect("ray should hit a wall");
+            assert_eq!(hit.texture_index, usize::MAX);
+            assert!(hit.perpendicular_distance <= 1.5 * 2.0_f32.sqrt());
+        }
+    }
+
+    #[test]
+    fn rays_across_a_ragged_map_hit_the_padding() {
+        let level = level(&["SSSSS", "S..", "S...S", "SSSSS"]);
+        let hit = cast(&level, vec2(1.5, 1.5), vec2(1.0, 0.0)).expect("ray should hit a wall");
+        assert_eq!(hit.texture_index, MISSING_TEXTURE);
+        assert_eq!(hit.perpendicular_distance, 1.5);
+        // A whole frame's worth of rays from open tiles, facing every way, as the renderer would cast them
+        for origin in [vec2(1.5, 1.5), vec2(2.5, 1.5), vec2(3.5, 2.5)] {
+            for direction in [vec2(1.0, 0.0), vec2(0.0, 1.0), vec2(-1.0, 0.0), vec2(0.0, -1.0)] {
+                let camera = direction.perp() * 0.66;
+                for x in 0..640 {
+                    let camera_x = 2.0 * x as f32 / 640.0 - 1.0;
+                    let hit = cast(&level, origin, direction + camera * camera_x).expect("ray should hit a wall");
+                    assert!(hit.perpendicular_distance.is_finite() && hit.perpendicular_distance < 5.0);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn enclosed_levels_are_valid() {
+        let level = level(&["SSSS", "S..S", "SSSS"]);
+        assert!(level.validate(vec2(1.5, 1.5)).is_ok());
+    }
+
+    #[test]
+    fn open_floor_reaching_the_edge_is_a_leak() {
+        let level = level(&["SSSS", "S...", "SSSS"]);
+        assert!(matches!(level.validate(vec2(1.5, 1.5)), Err(MapError::Leak { x: 3, y: 1 })));
+        assert!(matches!(level.validate(vec2(0.5, 0.5)), Err(MapError::SpawnInWall { x: 0, y: 0 })));
+    }
+
+    #[test]
+    fn rays_stop_at_the_render_distance() {
+        let corridor = format!("S{}S", ".".repeat(40));
+        let level = level(&[&"S".repeat(42), &corridor, &"S".repeat(42)]);
+        let (origin, direction) = (vec2(1.5, 1.5), vec2(1.0, 0.0));
+        assert!(cast_ray(&level.map, &level.doors, &level.secret_walls, origin, direction, 10.0).is_none());
+        assert!(cast_ray_through(&level.map, &level.doors, &level.secret_walls, origin, direction, 10.0).is_empty());
+        let hit = cast_ray(&level.map, &level.doors, &level.secret_walls, origin, direction, 50.0).expect("ray should reach the end");
+        assert_eq!(hit.perpendicular_distance, 39.5);
+    }
+
+    #[test]
+    fn walls_past_the_render_distance_are_dropped_from_see_through_hits() {
+        let level = level(&["SSSSSSSSSS", "S.L.....SS", "SSSSSSSSSS"]);
+        let (origin, direction) = (vec2(1.5, 1.5), vec2(1.0, 0.0));
+        // The ledge is short enough to see over, but the wall behind it is out of reach
+        let hits = cast_ray_through(&level.map, &level.doors, &level.secret_walls, origin, direction, 4.0);
+        assert_eq!(hits.len(), 1);
+        assert_eq!(hits[0].perpendicular_distance, 0.5);
+        let hits = cast_ray_through(&level.map, &level.doors, &level.secret_walls, origin, direction, 10.0);
+        assert_eq!(hits.iter().map(|hit| hit.perpendicular_distance).collect::<Vec<f32>>(), vec![0.5, 6.5]);
+    }
+
+    #[test]
+    fn locked_doors_stay_shut_without_the_key() {
+        let mut level = level(&["SSSSS", "S.D.S", "SSSSS"]);
+        level.doors[0].key_id = Some(2);
+        let mut keys = vec![1];
+        assert!(!level.doors[0].try_unlock(&mut keys));
+        assert_eq!(keys, vec![1]);
+        assert_eq!(level.doors[0].key_id, Some(2));
+        assert!(!level.doors[0].opening);
+        level.doors[0].advance(1.0 / DOOR_SPEED);
+        assert!(level.is_solid(2, 1));
+    }
+
+    #[test]
+    fn locked_doors_open_with_the_key_and_use_it_up() {
+        let mut level = level(&["SSSSS", "S.D.S", "SSSSS"]);
+        level.doors[0].key_id = Some(2);
+        let mut keys = vec![1, 2];
+        assert!(level.doors[0].try_unlock(&mut keys));
+        assert_eq!(keys, vec![1]);
+        assert_eq!(level.doors[0].key_id, None);
+        level.doors[0].opening = true;
+        level.doors[0].advance(1.0 / DOOR_SPEED);
+        assert!(level.doors[0].is_open());
+        assert!(!level.is_solid(2, 1));
+        // Unlocked for good, so it opens again without another key
+        assert!(level.doors[0].try_unlock(&mut keys));
+        assert_eq!(keys, vec![1]);
+    }
+
+    #[test]
+    fn teleporters_go_both_ways() {
+        let mut level = level(&["SSSSSSS", "S.....S", "SSSSSSS"]);
+        level.teleporters = find_teleporters(".......\n.1...1.\n").expect("teleporters should pair up");
+        let mut player = Player::new(vec2(1.25, 1.75), vec2(1.0, 0.0), 0.66);
+        assert!(player.use_teleporters(&level));
+        assert_eq!(player.position, vec2(5.25, 1.75));
+        assert_eq!(player.direction, vec2(1.0, 0.0));
+        // Stepping off and back on goes the other way
+        player.position = vec2(4.5, 1.5);
+        assert!(!player.use_teleporters(&level));
+        player.position = vec2(5.5, 1.5);
+        assert!(player.use_teleporters(&level));
+        assert_eq!(player.position, vec2(1.5, 1.5));
+    }
+
+    #[test]
+    fn arriving_at_a_teleporter_does_not_send_the_player_back() {
+        let mut level = level(&["SSSSSSS", "S.....S", "SSSSSSS"]);
+        level.teleporters = find_teleporters(".......\n.1...1.\n").expect("teleporters should pair up");
+        let mut player = Player::new(vec2(1.5, 1.5), vec2(1.0, 0.0), 0.66);
+        assert!(player.use_teleporters(&level));
+        for _ in 0..10 {
+            player.position += vec2(0.01, 0.0);
+            assert!(!player.use_teleporters(&level));
+        }
+        assert_eq!(player.teleport_arrival, Some((5, 1)));
+    }
+
+    #[test]
+    fn triggers_fire_once() {
+        let mut level = level(&["SSSSS", "S...S", "SSSSS"]);
+        let events = parse_events("a message Welcome\na open_door 3 1").expect("events should parse");
+        level.triggers = find_triggers(".....\n.a...\n.....", &events);
+        assert!(level.fire_triggers(2, 1).is_empty());
+        let fired = level.fire_triggers(1, 1);
+        assert_eq!(fired.len(), 2);
+        assert!(matches!(&fired[0], Event::Message(text) if text == "Welcome"));
+        assert!(matches!(fired[1], Event::OpenDoor { x: 3, y: 1 }));
+        // Stepping back onto the tile does nothing
+        assert!(level.fire_triggers(1, 1).is_empty());
+        assert!(level.triggers.iter().all(|trigger| trigger.fired));
+    }
+
+    #[test]
+    fn pushed_secret_walls_slide_open() {
+        let mut level = level(&["SSSSSS", "S.B..S", "SSSSSS"]);
+        level.secret_walls = find_secret_walls("......\n..>...\n......", &level.map);
+        assert_eq!(level.secret_walls.len(), 1);
+        assert!(level.push_secret_wall(2, 1));
+        // While sliding it blocks both tiles and is drawn on its own
+        assert!(level.map[1][2].is_none());
+        assert!(level.is_solid(2, 1) && level.is_solid(3, 1));
+        level.advance_secret_walls(0.5 / SECRET_WALL_SPEED);
+        let hit = cast(&level, vec2(1.5, 1.5), vec2(1.0, 0.0)).expect("ray should hit the sliding wall");
+        assert!((hit.perpendicular_distance - 1.0).abs() < 0.001);
+        assert_eq!(hit.texture_index, 1);
+        // Once fully pushed it's back in the map, one tile further along
+        level.advance_secret_walls(0.5 / SECRET_WALL_SPEED);
+        assert!(!level.secret_walls[0].is_moving());
+        assert!(!level.is_solid(2, 1));
+        assert_eq!(level.tile_at(3, 1).map(|tile| tile.texture(Face::West)), Some(1));
+        // And can't be pushed again
+        assert!(!level.push_secret_wall(2, 1));
+        assert!(!level.push_secret_wall(3, 1));
+    }
+
+    #[test]
+    fn transparent_walls_do_not_block_line_of_sight() {
+        let level = level(&["SSSSSSS", "S.G.G.S", "S.S...S", "SSSSSSS"]);
+        assert!(has_line_of_sight(&level, vec2(1.5, 1.5), vec2(5.5, 1.5)));
+        assert!(has_line_of_sight(&level, vec2(5.5, 1.5), vec2(1.5, 1.5)));
+        assert!(!has_line_of_sight(&level, vec2(1.5, 2.5), vec2(5.5, 2.5)));
+    }
+
+    #[test]
+    fn rays_pick_the_texture_of_the_face_they_hit() {
+        let mut level = level(&["SSSSS", "S...S", "S...S", "S...S", "SSSSS"]);
+        level.map[2][2] = Some(Tile::wall(0).with_face_textures([10, 11, 12, 13]));
+        let face_texture = |origin: Vec2, direction: Vec2| cast(&level, origin, direction).map(|hit| hit.texture_index);
+        assert_eq!(face_texture(vec2(1.5, 2.5), vec2(1.0, 0.0)), Some(13)); // Travelling east hits the west face
+        assert_eq!(face_texture(vec2(3.5, 2.5), vec2(-1.0, 0.0)), Some(11));
+        assert_eq!(face_texture(vec2(2.5, 1.5), vec2(0.0, 1.0)), Some(10));
+        assert_eq!(face_texture(vec2(2.5, 3.5), vec2(0.0, -1.0)), Some(12));
+    }
+
+    #[test]
+    fn faces_are_named_after_the_way_they_face() {
+        assert!(matches!(Face::hit_by(Side::EastWest, vec2(1.0, 0.3)), Face::West));
+        assert!(matches!(Face::hit_by(Side::EastWest, vec2(-1.0, 0.3)), Face::East));
+        assert!(matches!(Face::hit_by(Side::NorthSouth, vec2(0.3, 1.0)), Face::North));
+        assert!(matches!(Face::hit_by(Side::NorthSouth, vec2(0.3, -1.0)), Face::South));
+    }
+
+    #[test]
+    fn rays_hit_the_face_of_diagonal_walls() {
+        // The diagonal leans on the wall north of it, filling the north-west half of its cell
+        let level = level(&["SSSSS", "S/..S", "S...S", "S...S", "SSSSS"]);
+        let hit = cast(&level, vec2(3.5, 3.5), vec2(-1.0, -1.0)).expect("ray should hit the diagonal");
+        assert!(hit.diagonal);
+        assert_eq!(hit.tile, (1, 1));
+        assert!((hit.perpendicular_distance - 2.0).abs() < 0.001);
+        assert!((hit.wall_x - 0.5).abs() < 0.001);
+        // Entering the cell through its open half, partway up the face
+        let hit = cast(&level, vec2(2.5, 1.2), vec2(-1.0, 0.0)).expect("ray should hit the diagonal");
+        assert!(hit.diagonal);
+        assert!((hit.perpendicular_distance - 0.7).abs() < 0.001);
+    }
+
+    #[test]
+    fn rays_can_pass_the_open_half_of_diagonal_walls() {
+        let level = level(&["SSSSS", "S/..S", "S...S", "S...S", "SSSSS"]);
+        // Crossing the corner of the open half and leaving through the south of the cell before reaching the face
+        let hit = cast(&level, vec2(2.5, 1.8), vec2(-1.0, 0.2)).expect("ray should hit the wall behind");
+        assert!(!hit.diagonal);
+        assert_eq!(hit.tile, (0, 2));
+    }
+
+    #[test]
+    fn rays_starting_next_to_a_diagonal_hit_it() {
+        let level = level(&["SSSSS", "S/..S", "S...S", "S...S", "SSSSS"]);
+        let hit = cast(&level, vec2(1.8, 1.8), vec2(-1.0, -1.0)).expect("ray should hit the diagonal in its own cell");
+        assert!(hit.diagonal);
+        assert!((hit.perpendicular_distance - 0.3).abs() < 0.001);
+    }
+
+    #[test]
+    fn diagonal_walls_only_block_the_half_they_fill() {
+        let level = level(&["SSSSS", "S/..S", "S...S", "S...S", "SSSSS"]);
+        assert!(level.is_solid_at(vec2(1.2, 1.2)));
+        assert!(!level.is_solid_at(vec2(1.8, 1.8)));
+        let mut player = Player::new(vec2(2.5, 2.5), vec2(-1.0, -1.0), 0.66);
+        for _ in 0..40 {
+            player.try_move(vec2(-0.05, -0.05), &level);
+        }
+        let local = player.position - vec2(1.0, 1.0);
+        assert!(local.x + local.y > 1.0, "player at {} should be stopped by the diagonal", player.position);
+        assert!(!level.collides(player.position, COLLISION_RADIUS));
+    }
+
+    #[test]
+    fn decoration_grid_only_for_many_decorations() {
+        let map = vec![vec![None; 4]; 4];
+        assert!(decoration_grid(vec![vec2(1.5, 2.5); DECORATION_GRID_MIN - 1].into_iter(), &map).is_empty());
+        let grid = decoration_grid(vec![vec2(1.5, 2.5); DECORATION_GRID_MIN].into_iter(), &map);
+        assert_eq!(grid[2][1].len(), DECORATION_GRID_MIN);
+        // Past the edge of the map goes in the nearest tile
+        let grid = decoration_grid(vec![vec2(-3.0, 9.0); DECORATION_GRID_MIN].into_iter(), &map);
+        assert_eq!(grid[3][0].len(), DECORATION_GRID_MIN);
+    }
+
+    #[test]
+    fn grid_lookup_skips_tiles_behind_and_out_of_reach() {
+        // A corridor with one decoration per tile, numbered from west to east
+        let grid = vec![(0..40).map(|x| vec![x]).collect::<Vec<Vec<usize>>>()];
+        let player = Player::new(vec2(5.5, 0.5), vec2(1.0, 0.0), 0.66);
+        let visible = grid_in_view(&grid, &player, 10.0);
+        assert!(visible.contains(&5) && visible.contains(&15));
+        assert!(!visible.contains(&0));
+        assert!(!visible.contains(&30));
+    }
+}
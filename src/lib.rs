@@ -0,0 +1,101 @@
+mod audio;
+#[doc(hidden)]
+pub mod bench;
+mod config;
+mod demo;
+mod event_loop;
+mod level;
+mod parsing;
+mod pathfinding;
+mod player;
+mod render;
+mod sprites;
+mod state;
+
+use config::Config;
+use demo::{DemoPlayer, DemoRecorder};
+use parsing::load_level;
+use state::{GameState, CONFIG_PATH, run_headless};
+use ggez::input::mouse;
+
+/// Runs the game, reading the command line and config and opening the window
+pub fn run() {
+    // Usage: sagakar-raycasting [map path] [--render <output.png> [--compare <golden.png>]] [--record <demo.toml> | --replay <demo.toml>]
+    let args = std::env::args().skip(1).collect::<Vec<String>>();
+    let flag_value = |flag: &str| {
+        args.iter().position(|arg| arg == flag).and_then(|index| args.get(index + 1)).cloned()
+    };
+    let render_path = flag_value("--render");
+    let golden_path = flag_value("--compare");
+    let record_path = flag_value("--record");
+    let replay_path = flag_value("--replay");
+
+    let mut config = match Config::load(CONFIG_PATH) {
+        Ok(config) => config,
+        Err(error) => {
+            eprintln!("{error}");
+            std::process::exit(1);
+        }
+    };
+
+    // ----GGEZ setup----
+    let setup = ggez::conf::WindowSetup::default().title("Raycast test").vsync(config.vsync);
+    let builder = ggez::ContextBuilder::new("Raycast test", "sagakar").window_setup(setup);
+    let (mut context, events) = builder.build().expect("Failed to build context");
+    // Headless renders never show the window
+    let window_mode = match render_path {
+        Some(_) => ggez::conf::WindowMode::default().visible(false),
+        None => ggez::conf::WindowMode::default()
+        .resizable(true)
+        .borderless(true)
+        .fullscreen_type(ggez::conf::FullscreenType::Desktop),
+    };
+    context.gfx.set_mode(window_mode).expect("Failed to set window mode");
+
+    // ----Game state setup----
+    // A map path given on the command line takes precedence over the config
+    if let Some(map_path) = args.first().filter(|arg| !arg.starts_with("--")) {
+        config.map_path = map_path.clone();
+    }
+    let (level, spawn_position, spawn_direction) = match load_level(&context, &config) {
+        Ok(loaded) => loaded,
+        Err(error) => {
+            eprintln!("{error}");
+            std::process::exit(1);
+        }
+    };
+    // Create the texture hashmap
+    let mut state = GameState::new(
+        &mut context,
+        config,
+        level,
+        spawn_position,
+        spawn_direction
+    ).expect("Failed to construct game instance");
+
+    if let Some(render_path) = render_path {
+        if let Err(error) = run_headless(&mut context, &mut state, &render_path, golden_path.as_deref()) {
+            eprintln!("{error}");
+            std::process::exit(1);
+        }
+        return;
+    }
+
+    if let Some(record_path) = record_path {
+        state.recorder = Some(DemoRecorder::new(&record_path, &state.config.map_path));
+    }
+    if let Some(replay_path) = replay_path {
+        match DemoPlayer::load(&replay_path, &state.config.map_path) {
+            Ok(replay) => state.replay = Some(replay),
+            Err(error) => {
+                eprintln!("{error}");
+                std::process::exit(1);
+            }
+        }
+    }
+
+    // ----Put it all together----
+    mouse::set_cursor_grabbed(&mut context, true).expect("Failed to grab cursor");
+    mouse::set_cursor_hidden(&mut context, true);
+    event_loop::run(context, events, state);
+}
@@ -0,0 +1,721 @@
+use std::collections::HashMap;
+use crate::config::{Config, LegendDecoration, LegendItem, LegendLight, LegendTextures, Liquid};
+use crate::level::{Diagonal, Door, Event, Level, Light, SecretWall, Teleporter, Tile, Trigger, MISSING_TEXTURE, decoration_grid, layer_at, tile_at};
+use crate::render::{CeilingMode, FloorMode, Sky};
+use crate::sprites::{Decoration, Enemy, Item, ItemKind};
+use ggez::{graphics::Image, glam::{vec2, Vec2}, Context, GameError};
+
+// Misc parameters
+const MAX_LIGHTS: usize = 16; // Every light is checked for every floor and ceiling pixel, so there can't be too many
+
+/// Returns a section of a map file.
+/// A map file starts with the tile grid, which is returned for `None`.
+/// It can then have optional layers, each starting with a `[name]` header line:
+/// - `[floor]` and `[ceiling]` pick the texture of each tile's floor or ceiling,
+///   `0` being the default texture and `1`-`9` indexing into the `flats` of the texture manifest
+/// - `[decorations]` places a decoration from the config's `decoration_legend` in the middle of a tile,
+///   with `.` or a space leaving the tile empty
+/// - `[items]` places an item from the config's `item_legend` the same way
+/// - `[enemies]` places an enemy with `e` the same way
+/// - `[locks]` locks the door on a tile, `1`-`9` being the id of the key that unlocks it
+/// - `[teleporters]` links pairs of tiles marked with the same character, taking the player
+///   from either one to the other
+/// - `[lights]` places a light from the config's `light_legend` in the middle of a tile, the same way as decorations
+/// - `[lightmap]` paints the brightness of each tile, from `0` for pitch black to `9` for full brightness.
+///   Anything else, and tiles outside the layer, are fully bright. Walls take the brightness of the tile they face.
+/// - `[secrets]` turns walls into secret walls that slide one tile back when used,
+///   marked with `^`, `v`, `<` or `>` for the way they slide. The tile they slide onto has to be open floor.
+/// - `[lifts]` raises the player while standing on a tile, by `LIFT_STEP` for every step from `1`-`9`
+/// - `[sky]` opens up the ceiling of tiles marked with `*`, showing the sky or gradient above them instead.
+///   Every other tile keeps its ceiling
+/// - `[liquids]` floods the floor of a tile with a liquid from the config's `liquids`, with `.` or a space leaving it dry
+/// - `[triggers]` marks tiles with a character that names the events they set off
+/// - `[events]` lists what each trigger character sets off, see [`parse_events`]
+///
+/// Blank lines and comment lines are ignored in every section, see [`map_lines`].
+pub fn map_section<'a>(map_str: &'a str, section: Option<&str>) -> Option<&'a str> {
+    let mut current = None;
+    let mut start = 0;
+    let mut offset = 0;
+    for line in map_str.split_inclusive('\n') {
+        let trimmed = line.trim();
+        if trimmed.starts_with('[') && trimmed.ends_with(']') {
+            if current == section {
+                return Some(&map_str[start..offset]);
+            }
+            current = Some(&trimmed[1..trimmed.len() - 1]);
+            start = offset + line.len();
+        }
+        offset += line.len();
+    }
+    (current == section).then(|| &map_str[start..])
+}
+
+/// Returns the rows of a map section, skipping blank lines and comment lines.
+/// A comment line is one whose first non-whitespace character is `#`.
+fn map_lines(section_str: &str) -> impl Iterator<Item = &str> {
+    numbered_map_lines(section_str).map(|(_, line)| line)
+}
+
+/// Like [`map_lines`], but pairs each row with its line number in the section, counting from 1,
+/// so problems can be reported where they are in the file rather than in the grid
+fn numbered_map_lines(section_str: &str) -> impl Iterator<Item = (usize, &str)> {
+    section_str
+        .lines()
+        .enumerate()
+        .map(|(index, line)| (index + 1, line))
+        .filter(|(_, line)| !line.trim().is_empty() && !line.trim_start().starts_with('#'))
+}
+
+/// Converts a layer of digits to a matrix of texture indices.
+/// Anything that isn't a digit uses the default texture.
+fn parse_layer(layer_str: &str) -> Vec<Vec<usize>> {
+    map_lines(layer_str)
+        .map(|line| {
+            line.chars()
+                .map(|char| char.to_digit(10).unwrap_or(0) as usize)
+                .collect::<Vec<usize>>()
+        })
+        .collect::<Vec<Vec<usize>>>()
+}
+
+/// Converts a lightmap layer of digits to a matrix of brightnesses from 0 to 1, see [`map_section`]
+fn parse_light_map(layer_str: &str) -> Vec<Vec<f32>> {
+    map_lines(layer_str)
+        .map(|line| {
+            line.chars()
+                .map(|char| char.to_digit(10).map_or(1.0, |digit| digit as f32 / 9.0))
+                .collect::<Vec<f32>>()
+        })
+        .collect::<Vec<Vec<f32>>>()
+}
+
+/// Converts a sky layer to a matrix of whether each tile is open to the sky, see [`map_section`]
+fn parse_sky_map(layer_str: &str) -> Vec<Vec<bool>> {
+    map_lines(layer_str)
+        .map(|line| line.chars().map(|char| char == '*').collect::<Vec<bool>>())
+        .collect::<Vec<Vec<bool>>>()
+}
+
+/// Converts a liquids layer to a matrix of liquid map characters, see [`map_section`].
+/// Characters missing from `liquids` are reported and left dry.
+fn parse_liquid_map(layer_str: &str, liquids: &HashMap<char, Liquid>) -> Vec<Vec<Option<char>>> {
+    numbered_map_lines(layer_str)
+        .map(|(line_number, line)| {
+            line.chars()
+                .enumerate()
+                .map(|(x, char)| match char {
+                    '.' | ' ' => None,
+                    char if liquids.contains_key(&char) => Some(char),
+                    char => {
+                        eprintln!("Warning: unknown liquid '{char}' at line {line_number} of the liquids layer, column {}", x + 1);
+                        None
+                    }
+                })
+                .collect::<Vec<Option<char>>>()
+        })
+        .collect::<Vec<Vec<Option<char>>>>()
+}
+
+/// The built-in map characters, plus any extra wall characters from the config.
+/// Characters mapped to `None` are open floor.
+pub fn tile_legend(config: &Config) -> HashMap<char, Option<Tile>> {
+    let mut legend = HashMap::from([
+        (' ', Some(Tile::wall(MISSING_TEXTURE))), // Outside the level, like anything past the edge of the map
+        ('.', None),
+        ('^', None), // Spawn markers stand on open floor, see `find_spawn`
+        ('v', None),
+        ('<', None),
+        ('>', None),
+        ('S', Some(Tile::wall(0))),
+        ('B', Some(Tile::wall(1))),
+        ('W', Some(Tile::wall(2))),
+        ('L', Some(Tile::wall(0).with_height(0.5))), // Low ledge
+        ('D', Some(Tile::thin_wall(2))), // Doors, see `find_doors`
+        ('G', Some(Tile::thin_wall(3).transparent())), // Grates, using the fourth texture in the manifest, which should have see-through parts
+        // Diagonal walls, drawn as the line they cut their cell along. They fill the north half of their cell
+        // when there's a wall to lean on there, and the south half otherwise, see `orient_diagonals`
+        ('/', Some(Tile::wall(0).diagonal(Diagonal::NorthWest))),
+        ('\\', Some(Tile::wall(0).diagonal(Diagonal::NorthEast))),
+    ]);
+    legend.extend(config.tile_legend.iter().map(|(char, textures)| {
+        let tile = match textures {
+            LegendTextures::All(texture) => Tile::wall(*texture),
+            LegendTextures::Faces(textures) => Tile::wall(textures[0]).with_face_textures(*textures),
+        };
+        (*char, Some(tile))
+    }));
+    legend
+}
+
+/// Parses the tile grid of a map using `legend` to look up each character.
+/// Characters missing from the legend are reported and become walls with a missing texture.
+/// Rows shorter than the longest one are padded with solid walls, so the grid is always rectangular.
+pub fn parse_map(map_str: &str, legend: &HashMap<char, Option<Tile>>) -> Vec<Vec<Option<Tile>>> {
+    let width = map_lines(map_str).map(|line| line.chars().count()).max().unwrap_or(0);
+    numbered_map_lines(map_str)
+        .map(|(line_number, line)| {
+            let mut row = line.chars()
+                .enumerate()
+                .map(|(x, char)| match legend.get(&char) {
+                    Some(tile) => *tile,
+                    None => {
+                        eprintln!("Warning: unknown map character '{char}' at line {line_number}, column {}", x + 1);
+                        Some(Tile::wall(MISSING_TEXTURE))
+                    }
+                })
+                .collect::<Vec<Option<Tile>>>();
+            row.resize(width, Some(Tile::wall(MISSING_TEXTURE)));
+            row
+        })
+        .collect::<Vec<Vec<Option<Tile>>>>()
+}
+
+/// Turns each diagonal wall in the map towards the walls next to it.
+/// Diagonals from the legend fill one of the north corners of their cell, and are flipped to the opposite south corner
+/// unless one of the two walls beside that north corner is there for them to lean on.
+pub fn orient_diagonals(map: &mut [Vec<Option<Tile>>]) {
+    let is_wall = |map: &[Vec<Option<Tile>>], x: i32, y: i32| tile_at(map, x, y).is_some_and(|tile| tile.diagonal.is_none());
+    for y in 0..map.len() {
+        for x in 0..map[y].len() {
+            let Some(Tile { diagonal: Some(diagonal), .. }) = map[y][x] else {
+                continue;
+            };
+            let (x, y) = (x as i32, y as i32);
+            let leaning = match diagonal {
+                Diagonal::NorthWest => is_wall(map, x, y - 1) || is_wall(map, x - 1, y),
+                Diagonal::NorthEast => is_wall(map, x, y - 1) || is_wall(map, x + 1, y),
+                Diagonal::SouthEast | Diagonal::SouthWest => true,
+            };
+            if !leaning {
+                if let Some(tile) = &mut map[y as usize][x as usize] {
+                    tile.diagonal = Some(diagonal.opposite());
+                }
+            }
+        }
+    }
+}
+
+/// Converts a map drawn as an image, one pixel per tile, into the tile grid of an ASCII map
+/// by looking up the map character each pixel's color stands for in `palette`.
+/// The grid is parsed like any other, so spawns and doors work the same way, but image maps have no layers.
+/// Colors missing from the palette are reported and become walls with a missing texture.
+fn parse_map_image(image: &image::RgbImage, palette: &HashMap<char, [u8; 3]>) -> String {
+    let chars_by_color = palette.iter().map(|(char, color)| (*color, *char)).collect::<HashMap<[u8; 3], char>>();
+    let mut map_string = String::new();
+    for (y, row) in image.rows().enumerate() {
+        for (x, pixel) in row.enumerate() {
+            let char = match chars_by_color.get(&pixel.0) {
+                Some(char) => *char,
+                None => {
+                    let [r, g, b] = pixel.0;
+                    eprintln!("Warning: unknown map color ({r}, {g}, {b}) at row {}, column {}", y + 1, x + 1);
+                    ' '
+                }
+            };
+            map_string.push(char);
+        }
+        map_string.push('\n');
+    }
+    map_string
+}
+
+/// Finds the doors in an ASCII art representation of a map.
+/// Doors with a nonzero key id in `locks` start out locked.
+pub fn find_doors(map_str: &str, locks: &[Vec<usize>]) -> Vec<Door> {
+    map_lines(map_str)
+        .enumerate()
+        .flat_map(|(y, line)| {
+            line.chars()
+                .enumerate()
+                .filter(|(_, char)| *char == 'D')
+                .map(move |(x, _)| {
+                    let key_id = match layer_at(locks, x as i32, y as i32) {
+                        0 => None,
+                        key_id => Some(key_id as u32),
+                    };
+                    Door::new(x as i32, y as i32, key_id)
+                })
+        })
+        .collect::<Vec<Door>>()
+}
+
+/// Finds the player spawn in an ASCII art representation of a map, marked by `^`, `v`, `<` or `>`
+/// depending on which way the player should face. Returns the spawn position and direction.
+fn find_spawn(map_str: &str) -> Option<(Vec2, Vec2)> {
+    map_lines(map_str)
+        .enumerate()
+        .find_map(|(y, line)| {
+            line.chars().enumerate().find_map(|(x, char)| {
+                let direction = match char {
+                    '^' => vec2(0.0, -1.0),
+                    'v' => vec2(0.0, 1.0),
+                    '<' => vec2(-1.0, 0.0),
+                    '>' => vec2(1.0, 0.0),
+                    _ => return None,
+                };
+                Some((vec2(x as f32 + 0.5, y as f32 + 0.5), direction))
+            })
+        })
+}
+
+/// Finds what's placed in a placement layer of a map, like decorations or items, see [`map_section`]
+fn find_placements(layer_str: &str) -> Vec<(char, Vec2)> {
+    map_lines(layer_str)
+        .enumerate()
+        .flat_map(|(y, line)| {
+            line.chars()
+                .enumerate()
+                .filter(|(_, char)| *char != '.' && *char != ' ')
+                .map(move |(x, char)| (char, vec2(x as f32 + 0.5, y as f32 + 0.5)))
+        })
+        .collect::<Vec<(char, Vec2)>>()
+}
+
+/// Links up the teleporters in a teleporters layer of a map, see [`map_section`].
+/// Every character has to mark exactly two tiles, which become a teleporter going each way.
+pub fn find_teleporters(layer_str: &str) -> Result<Vec<Teleporter>, GameError> {
+    let mut ends: HashMap<char, Vec<(i32, i32)>> = HashMap::new();
+    for (char, position) in find_placements(layer_str) {
+        ends.entry(char).or_default().push((position.x.floor() as i32, position.y.floor() as i32));
+    }
+    let mut teleporters = Vec::new();
+    for (char, tiles) in ends {
+        let [a, b] = tiles[..] else {
+            return Err(GameError::ResourceLoadError(format!(
+                "Teleporter '{char}' marks {} tiles instead of a pair", tiles.len()
+            )));
+        };
+        teleporters.push(Teleporter { source: a, destination: b });
+        teleporters.push(Teleporter { source: b, destination: a });
+    }
+    Ok(teleporters)
+}
+
+/// Parses the events section of a map, see [`map_section`].
+/// Each line is a trigger character followed by an event and its arguments:
+/// - `message <text>`
+/// - `open_door <x> <y>`
+/// - `spawn_enemy <x> <y>`
+///
+/// A character can be listed on several lines to set off several events at once.
+pub fn parse_events(events_str: &str) -> Result<Vec<(char, Event)>, GameError> {
+    map_lines(events_str)
+        .map(|line| {
+            let invalid = |reason: &str| GameError::ResourceLoadError(format!("Invalid event \"{}\": {reason}", line.trim()));
+            let mut words = line.split_whitespace();
+            let mut trigger_chars = words.next().unwrap_or_default().chars();
+            let (Some(char), None) = (trigger_chars.next(), trigger_chars.next()) else {
+                return Err(invalid("it has to start with a single trigger character"));
+            };
+            let name = words.next().ok_or_else(|| invalid("missing the event name"))?;
+            let arguments = words.collect::<Vec<&str>>();
+            let tile = || match arguments[..] {
+                [x, y] => match (x.parse::<i32>(), y.parse::<i32>()) {
+                    (Ok(x), Ok(y)) => Ok((x, y)),
+                    _ => Err(invalid("the tile coordinates have to be whole numbers")),
+                },
+                _ => Err(invalid("expected the x and y of a tile")),
+            };
+            let event = match name {
+                "message" => Event::Message(arguments.join(" ")),
+                "open_door" => tile().map(|(x, y)| Event::OpenDoor { x, y })?,
+                "spawn_enemy" => tile().map(|(x, y)| Event::SpawnEnemy { x, y })?,
+                _ => return Err(invalid("unknown event")),
+            };
+            Ok((char, event))
+        })
+        .collect::<Result<Vec<(char, Event)>, GameError>>()
+}
+
+/// Places a trigger for every event of every character in a triggers layer of a map
+pub fn find_triggers(layer_str: &str, events: &[(char, Event)]) -> Vec<Trigger> {
+    find_placements(layer_str)
+        .into_iter()
+        .flat_map(|(char, position)| {
+            let (x, y) = (position.x.floor() as i32, position.y.floor() as i32);
+            let tile_events = events.iter().filter(move |(key, _)| *key == char).collect::<Vec<_>>();
+            if tile_events.is_empty() {
+                eprintln!("Warning: trigger '{char}' at ({x}, {y}) has no events");
+            }
+            tile_events.into_iter().map(move |(_, event)| Trigger { x, y, event: event.clone(), fired: false })
+        })
+        .collect::<Vec<Trigger>>()
+}
+
+/// Finds the secret walls in a secrets layer of a map, see [`map_section`].
+/// Markers that aren't on a wall, or that would slide a wall into anything but open floor, are reported and skipped.
+pub fn find_secret_walls(layer_str: &str, map: &[Vec<Option<Tile>>]) -> Vec<SecretWall> {
+    find_placements(layer_str)
+        .into_iter()
+        .filter_map(|(char, position)| {
+            let (x, y) = (position.x.floor() as i32, position.y.floor() as i32);
+            let direction = match char {
+                '^' => (0, -1),
+                'v' => (0, 1),
+                '<' => (-1, 0),
+                '>' => (1, 0),
+                _ => {
+                    eprintln!("Warning: unknown secret wall direction '{char}' at ({x}, {y})");
+                    return None;
+                }
+            };
+            // Unlike `tile_at`, anything outside the map counts as missing rather than as a wall
+            let tile_in_map = |x: i32, y: i32| match x >= 0 && y >= 0 {
+                true => map.get(y as usize).and_then(|row| row.get(x as usize)).copied(),
+                false => None,
+            };
+            let Some(Some(tile)) = tile_in_map(x, y) else {
+                eprintln!("Warning: secret wall at ({x}, {y}) isn't on a wall");
+                return None;
+            };
+            let Some(None) = tile_in_map(x + direction.0, y + direction.1) else {
+                eprintln!("Warning: secret wall at ({x}, {y}) has no open floor to slide onto");
+                return None;
+            };
+            Some(SecretWall { x, y, direction, tile, offset: 0.0, pushing: false })
+        })
+        .collect::<Vec<SecretWall>>()
+}
+
+/// Creates the lights placed in a map using `legend`, keeping only the first `MAX_LIGHTS`
+fn build_lights(placements: &[(char, Vec2)], legend: &HashMap<char, LegendLight>) -> Result<Vec<Light>, GameError> {
+    if placements.len() > MAX_LIGHTS {
+        eprintln!("Warning: the map has {} lights, only the first {MAX_LIGHTS} are used", placements.len());
+    }
+    placements
+        .iter()
+        .take(MAX_LIGHTS)
+        .map(|(char, position)| {
+            let Some(light) = legend.get(char) else {
+                return Err(GameError::ResourceLoadError(format!(
+                    "Unknown light '{char}' at ({}, {})", position.x.floor(), position.y.floor()
+                )));
+            };
+            Ok(Light { position: *position, color: light.color, radius: light.radius })
+        })
+        .collect::<Result<Vec<Light>, GameError>>()
+}
+
+/// Creates the decorations placed in a map using `legend`.
+/// A decoration whose sprite fails to load is reported and left out, so a missing sprite doesn't stop the level loading.
+fn build_decorations(ctx: &Context, placements: &[(char, Vec2)], legend: &HashMap<char, LegendDecoration>) -> Result<Vec<Decoration>, GameError> {
+    let mut decorations = Vec::with_capacity(placements.len());
+    for (char, position) in placements {
+        let Some(LegendDecoration { sprite: sprite_path, scale, vertical_offset, sound: sound_path }) = legend.get(char) else {
+            return Err(GameError::ResourceLoadError(format!(
+                "Unknown decoration '{char}' at ({}, {})", position.x.floor(), position.y.floor()
+            )));
+        };
+        match Decoration::new(ctx, sprite_path, *position, *scale) {
+            Ok(decoration) => {
+                let decoration = decoration.with_vertical_offset(*vertical_offset);
+                decorations.push(match sound_path {
+                    Some(sound_path) => decoration.with_sound(ctx, sound_path),
+                    None => decoration,
+                });
+            }
+            Err(error) => eprintln!(
+                "Warning: skipping decoration '{char}' at ({}, {}), could not load {sprite_path}: {error}",
+                position.x.floor(), position.y.floor()
+            ),
+        }
+    }
+    Ok(decorations)
+}
+
+/// Creates the items placed in a map using `legend`
+fn build_items(ctx: &Context, placements: &[(char, Vec2)], legend: &HashMap<char, LegendItem>) -> Result<Vec<Item>, GameError> {
+    placements
+        .iter()
+        .map(|(char, position)| {
+            let Some(item) = legend.get(char) else {
+                return Err(GameError::ResourceLoadError(format!(
+                    "Unknown item '{char}' at ({}, {})", position.x.floor(), position.y.floor()
+                )));
+            };
+            Ok(Item {
+                kind: item.key.map_or(ItemKind::Coin, ItemKind::Key),
+                decoration: Decoration::new(ctx, &item.sprite, *position, item.scale)?,
+            })
+        })
+        .collect::<Result<Vec<Item>, GameError>>()
+}
+
+/// Creates the enemies placed in a map
+fn build_enemies(ctx: &Context, placements: &[(char, Vec2)]) -> Result<Vec<Enemy>, GameError> {
+    placements
+        .iter()
+        .map(|(char, position)| match char {
+            'e' => Enemy::new(ctx, *position),
+            _ => Err(GameError::ResourceLoadError(format!(
+                "Unknown enemy '{char}' at ({}, {})", position.x.floor(), position.y.floor()
+            ))),
+        })
+        .collect::<Result<Vec<Enemy>, GameError>>()
+}
+
+/// Loads and validates the level at `config.map_path`.
+/// Returns the level along with the player's spawn position and direction.
+pub fn load_level(ctx: &Context, config: &Config) -> Result<(Level, Vec2, Vec2), GameError> {
+    let map_path = &config.map_path;
+    let map_string = match map_path.ends_with(".png") {
+        true => image::open(map_path)
+            .map(|image| parse_map_image(&image.to_rgb8(), &config.map_palette))
+            .map_err(|error| GameError::ResourceLoadError(format!("Failed reading map image {map_path}: {error}")))?,
+        false => std::fs::read_to_string(map_path)
+            .map_err(|error| GameError::ResourceLoadError(format!("Failed reading map file {map_path}: {error}")))?,
+    };
+    let tiles_string = map_section(&map_string, None).unwrap_or_default();
+    let placements = map_section(&map_string, Some("decorations")).map(find_placements).unwrap_or_default();
+    let decorations = build_decorations(ctx, &placements, &config.decoration_legend)
+        .map_err(|error| GameError::ResourceLoadError(format!("Failed placing decorations from {map_path}: {error}")))?;
+    let placements = map_section(&map_string, Some("enemies")).map(find_placements).unwrap_or_default();
+    let enemies = build_enemies(ctx, &placements)
+        .map_err(|error| GameError::ResourceLoadError(format!("Failed placing enemies from {map_path}: {error}")))?;
+    let placements = map_section(&map_string, Some("items")).map(find_placements).unwrap_or_default();
+    let items = build_items(ctx, &placements, &config.item_legend)
+        .map_err(|error| GameError::ResourceLoadError(format!("Failed placing items from {map_path}: {error}")))?;
+    let teleporters = map_section(&map_string, Some("teleporters")).map(find_teleporters).transpose()
+        .map_err(|error| GameError::ResourceLoadError(format!("Failed placing teleporters from {map_path}: {error}")))?
+        .unwrap_or_default();
+    let events = map_section(&map_string, Some("events")).map(parse_events).transpose()
+        .map_err(|error| GameError::ResourceLoadError(format!("Failed reading events from {map_path}: {error}")))?
+        .unwrap_or_default();
+    let triggers = map_section(&map_string, Some("triggers")).map(|layer| find_triggers(layer, &events)).unwrap_or_default();
+    let placements = map_section(&map_string, Some("lights")).map(find_placements).unwrap_or_default();
+    let lights = build_lights(&placements, &config.light_legend)
+        .map_err(|error| GameError::ResourceLoadError(format!("Failed placing lights from {map_path}: {error}")))?;
+    let mut map = parse_map(tiles_string, &tile_legend(config));
+    orient_diagonals(&mut map);
+    let secret_walls = map_section(&map_string, Some("secrets")).map(|layer| find_secret_walls(layer, &map)).unwrap_or_default();
+    let decoration_grid = decoration_grid(decorations.iter().map(|decoration| decoration.position), &map);
+    let level = Level {
+        map,
+        doors: find_doors(tiles_string, &map_section(&map_string, Some("locks")).map(parse_layer).unwrap_or_default()),
+        floor_map: map_section(&map_string, Some("floor")).map(parse_layer).unwrap_or_default(),
+        ceiling_map: map_section(&map_string, Some("ceiling")).map(parse_layer).unwrap_or_default(),
+        decorations,
+        decoration_grid,
+        items,
+        enemies,
+        teleporters,
+        triggers,
+        lights,
+        secret_walls,
+        light_map: map_section(&map_string, Some("lightmap")).map(parse_light_map).unwrap_or_default(),
+        lift_map: map_section(&map_string, Some("lifts")).map(parse_layer).unwrap_or_default(),
+        sky_map: map_section(&map_string, Some("sky")).map(parse_sky_map).unwrap_or_default(),
+        liquid_map: map_section(&map_string, Some("liquids")).map(|layer| parse_liquid_map(layer, &config.liquids)).unwrap_or_default(),
+        floor_mode: match (config.gradient_floor, &config.floor_parallax) {
+            (true, _) => FloorMode::Gradient,
+            (false, Some(path)) => FloorMode::Parallax(Image::from_path(ctx, path)?),
+            (false, None) => FloorMode::Tiled,
+        },
+        ceiling_mode: match (config.gradient_ceiling, &config.ceiling_parallax) {
+            (true, _) => CeilingMode::Gradient,
+            (false, _) if !config.sky.is_empty() => CeilingMode::Sky(Sky::load(ctx, &config.sky)?),
+            (false, Some(path)) => CeilingMode::Parallax(Image::from_path(ctx, path)?),
+            (false, None) => CeilingMode::Tiled,
+        },
+    };
+    let (spawn_position, spawn_direction) = find_spawn(tiles_string).unwrap_or((vec2(3.0, 3.0), vec2(0.0, -1.0)));
+    level.validate(spawn_position)
+        .map_err(|error| GameError::ResourceLoadError(format!("Invalid map {map_path}: {error}")))?;
+    Ok((level, spawn_position, spawn_direction))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::level::{Diagonal, Face, MapError, MISSING_TEXTURE};
+    use crate::level::tests::level;
+
+    #[test]
+    fn spawn_markers_set_position_and_direction() {
+        let map = "SSSS\nS.>S\nSSSS";
+        assert_eq!(find_spawn(map), Some((vec2(2.5, 1.5), vec2(1.0, 0.0))));
+        for (marker, direction) in [('^', vec2(0.0, -1.0)), ('v', vec2(0.0, 1.0)), ('<', vec2(-1.0, 0.0))] {
+            let map = format!("SSS\nS{marker}S\nSSS");
+            assert_eq!(find_spawn(&map), Some((vec2(1.5, 1.5), direction)));
+        }
+    }
+
+    #[test]
+    fn spawn_markers_stand_on_open_floor() {
+        let level = level(&["SSSS", "S.>S", "SSSS"]);
+        assert!(level.tile_at(2, 1).is_none());
+    }
+
+    #[test]
+    fn maps_without_a_spawn_marker_have_no_spawn() {
+        assert_eq!(find_spawn("SSS\nS.S\nSSS"), None);
+    }
+
+    #[test]
+    fn ragged_maps_are_padded_with_walls() {
+        let level = level(&["SSSSS", "S..", "S...S", "SSSSS"]);
+        assert!(level.map.iter().all(|row| row.len() == 5));
+        assert_eq!(level.tile_at(3, 1).map(|tile| tile.textures), Some([MISSING_TEXTURE; 4]));
+        assert_eq!(level.tile_at(4, 1).map(|tile| tile.textures), Some([MISSING_TEXTURE; 4]));
+        assert!(level.tile_at(3, 2).is_none());
+    }
+
+    #[test]
+    fn comments_before_the_map_do_not_shift_the_spawn() {
+        let map = "# The spawn is on the second row of tiles\n\nSSS\nSvS\nSSS";
+        assert_eq!(find_spawn(map), Some((vec2(1.5, 1.5), vec2(0.0, 1.0))));
+    }
+
+    #[test]
+    fn blank_lines_and_comments_are_not_map_rows() {
+        let level = level(&["# A small room", "SSS", "", "S.S", "  # Indented comments count too", "SSS"]);
+        assert_eq!(level.map.len(), 3);
+        assert!(level.tile_at(1, 1).is_none());
+    }
+
+    /// The grid of a parsed map, with each wall as its texture
+    fn parse_textures(map_str: &str) -> Vec<Vec<Option<usize>>> {
+        parse_map(map_str, &tile_legend(&Config::default()))
+            .iter()
+            .map(|row| row.iter().map(|tile| tile.map(|tile| tile.texture(Face::North))).collect())
+            .collect()
+    }
+
+    #[test]
+    fn parse_map_builds_the_grid() {
+        let grid = parse_textures("SBW\nS.W\nSBW");
+        assert_eq!(grid.len(), 3);
+        assert!(grid.iter().all(|row| row.len() == 3));
+        assert_eq!(grid, vec![
+            vec![Some(0), Some(1), Some(2)],
+            vec![Some(0), None, Some(2)],
+            vec![Some(0), Some(1), Some(2)],
+        ]);
+    }
+
+    #[test]
+    fn parse_map_reads_floor_as_open() {
+        assert_eq!(parse_textures("..\n.."), vec![vec![None, None], vec![None, None]]);
+    }
+
+    #[test]
+    fn parse_map_makes_unknown_characters_missing_walls() {
+        assert_eq!(MISSING_TEXTURE, usize::MAX);
+        assert_eq!(parse_textures("S?S"), vec![vec![Some(0), Some(MISSING_TEXTURE), Some(0)]]);
+    }
+
+    #[test]
+    fn parse_map_treats_spaces_as_outside_the_level() {
+        // Spaces mark where the level doesn't reach, and are solid like the edge of the map.
+        // Lines of nothing but whitespace are skipped entirely
+        let grid = parse_textures("  \t \n SSS \n S.S\n SSS\n\n");
+        assert_eq!(grid.len(), 3);
+        assert!(grid.iter().all(|row| row.len() == 5));
+        assert_eq!(grid[1], vec![Some(MISSING_TEXTURE), Some(0), None, Some(0), Some(MISSING_TEXTURE)]);
+    }
+
+    #[test]
+    fn parse_map_skips_comments() {
+        let grid = parse_textures("# The start room\nSSS\n  # Between rows\nS.S\nSSS\n# The end");
+        assert_eq!(grid, parse_textures("SSS\nS.S\nSSS"));
+    }
+
+    #[test]
+    fn parse_map_uses_the_config_legend() {
+        let mut config = Config::default();
+        config.tile_legend.insert('X', LegendTextures::All(7));
+        assert_eq!(parse_map("SX", &tile_legend(&config))[0][1].map(|tile| tile.textures), Some([7; 4]));
+        assert_eq!(parse_textures("SX"), vec![vec![Some(0), Some(MISSING_TEXTURE)]]);
+    }
+
+    #[test]
+    fn doors_parse_their_locks() {
+        let doors = find_doors("SSSSS\nSD.DS\nSSSSS", &parse_layer("00000\n00030\n00000"));
+        assert_eq!(doors.iter().map(|door| (door.x, door.y, door.key_id)).collect::<Vec<(i32, i32, Option<u32>)>>(), vec![
+            (1, 1, None),
+            (3, 1, Some(3)),
+        ]);
+    }
+
+    #[test]
+    fn unpaired_teleporters_are_rejected() {
+        assert!(find_teleporters(".1.1.1").is_err());
+        assert!(find_teleporters(".1...").is_err());
+    }
+
+    #[test]
+    fn teleporters_inside_walls_are_rejected() {
+        let mut level = level(&["SSSSSSS", "S.....S", "SSSSSSS"]);
+        level.teleporters = find_teleporters(".......\n.1.....\n.....1.").expect("teleporters should pair up");
+        assert!(matches!(level.validate(vec2(2.5, 1.5)), Err(MapError::TeleporterInWall { x: 5, y: 2 })));
+        level.teleporters = find_teleporters(".......\n.1...1.").expect("teleporters should pair up");
+        assert!(level.validate(vec2(2.5, 1.5)).is_ok());
+    }
+
+    #[test]
+    fn invalid_events_are_rejected() {
+        assert!(parse_events("ab message Two characters").is_err());
+        assert!(parse_events("a explode").is_err());
+        assert!(parse_events("a open_door 3").is_err());
+        assert!(parse_events("# Only a comment\n\n").is_ok_and(|events| events.is_empty()));
+    }
+
+    #[test]
+    fn secret_walls_need_floor_to_slide_onto() {
+        let level = level(&["SSSSS", "S.BBS", "SSSSS"]);
+        assert!(find_secret_walls(".....\n..>..\n.....", &level.map).is_empty());
+        assert!(find_secret_walls(".....\n.>...\n.....", &level.map).is_empty());
+        assert_eq!(find_secret_walls(".....\n..<..\n.....", &level.map).len(), 1);
+    }
+
+    #[test]
+    fn image_maps_turn_colors_into_tiles() {
+        let palette = Config::default().map_palette;
+        let (stone, brick, floor, spawn) = ([0, 0, 0], [128, 0, 0], [255, 255, 255], [255, 0, 0]);
+        let pixels = [
+            stone, brick, stone,
+            stone, spawn, stone,
+            stone, floor, stone,
+            stone, stone, stone,
+        ];
+        let image = image::RgbImage::from_raw(3, 4, pixels.concat()).expect("pixels should fill the image");
+        let map_str = parse_map_image(&image, &palette);
+        assert_eq!(map_str, "SBS\nS^S\nS.S\nSSS\n");
+        assert_eq!(parse_textures(&map_str)[0], vec![Some(0), Some(1), Some(0)]);
+        assert_eq!(find_spawn(&map_str), Some((vec2(1.5, 1.5), vec2(0.0, -1.0))));
+    }
+
+    #[test]
+    fn unknown_image_map_colors_are_outside_the_level() {
+        let image = image::RgbImage::from_raw(2, 1, vec![1, 2, 3, 0, 0, 0]).expect("pixels should fill the image");
+        assert_eq!(parse_map_image(&image, &Config::default().map_palette), " S\n");
+    }
+
+    #[test]
+    fn diagonals_without_a_wall_to_lean_on_flip_south() {
+        let floating = level(&["SSSSS", "S...S", "S./.S", "S...S", "SSSSS"]);
+        assert!(matches!(floating.map[2][2].and_then(|tile| tile.diagonal), Some(Diagonal::SouthEast)));
+        let leaning = level(&["SSSSS", "S/..S", "S...S", "SSSSS"]);
+        assert!(matches!(leaning.map[1][1].and_then(|tile| tile.diagonal), Some(Diagonal::NorthWest)));
+    }
+
+    #[test]
+    fn map_lines_keep_their_line_numbers() {
+        let section = "SSS\n\n# A comment\nS.S\n   # An indented comment\nSSS\n";
+        let lines = numbered_map_lines(section).collect::<Vec<(usize, &str)>>();
+        assert_eq!(lines, vec![(1, "SSS"), (4, "S.S"), (6, "SSS")]);
+        assert_eq!(map_lines(section).collect::<Vec<&str>>(), vec!["SSS", "S.S", "SSS"]);
+    }
+
+    #[test]
+    fn lights_come_from_the_config_legend() {
+        let mut config = Config::default();
+        config.light_legend.insert('x', LegendLight { color: [0.2, 0.4, 0.6], radius: 3.0 });
+        let lights = build_lights(&[('x', vec2(1.5, 2.5)), ('t', vec2(3.5, 1.5))], &config.light_legend).expect("lights should be in the legend");
+        assert_eq!(lights[0].color, [0.2, 0.4, 0.6]);
+        assert_eq!(lights[0].radius, 3.0);
+        assert_eq!(lights[1].radius, config.light_legend[&'t'].radius);
+        assert!(build_lights(&[('?', vec2(1.5, 1.5))], &config.light_legend).is_err());
+    }
+}
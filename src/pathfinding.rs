@@ -0,0 +1,102 @@
+use std::cmp::Reverse;
+use std::collections::{BinaryHeap, HashMap};
+
+/// Finds the shortest path between two tiles with A*, moving only horizontally and vertically.
+/// Staying 4-connected keeps enemies from cutting diagonally past the corners of walls they'd collide with.
+/// `walkable` tells which tiles can be entered, and must be false everywhere outside the map.
+/// It takes a closure rather than the tile grid because closed doors block the way too,
+/// and those live in the level rather than in the grid.
+/// Returns the tiles along the path, starting with `start` and ending with `goal`,
+/// or `None` if the goal can't be reached.
+pub fn astar(walkable: impl Fn((i32, i32)) -> bool, start: (i32, i32), goal: (i32, i32)) -> Option<Vec<(i32, i32)>> {
+    let heuristic = |(x, y): (i32, i32)| (goal.0 - x).abs() + (goal.1 - y).abs();
+    let mut came_from = HashMap::new();
+    let mut cost_so_far = HashMap::from([(start, 0)]);
+    // Ties are broken on the tile itself so paths come out the same every time
+    let mut frontier = BinaryHeap::from([Reverse((heuristic(start), start))]);
+    while let Some(Reverse((_, current))) = frontier.pop() {
+        if current == goal {
+            let mut path = vec![goal];
+            while let Some(previous) = came_from.get(path.last()?) {
+                path.push(*previous);
+            }
+            path.reverse();
+            return Some(path);
+        }
+        let (x, y) = current;
+        for neighbor in [(x + 1, y), (x - 1, y), (x, y + 1), (x, y - 1)] {
+            if !walkable(neighbor) {
+                continue;
+            }
+            let cost = cost_so_far[&current] + 1;
+            if cost_so_far.get(&neighbor).is_some_and(|known_cost| *known_cost <= cost) {
+                continue;
+            }
+            cost_so_far.insert(neighbor, cost);
+            came_from.insert(neighbor, current);
+            frontier.push(Reverse((cost + heuristic(neighbor), neighbor)));
+        }
+    }
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Walkability for a map drawn with `#` for walls, where everything past its edges is a wall
+    fn walkable<'a>(rows: &'a [&str]) -> impl Fn((i32, i32)) -> bool + 'a {
+        move |(x, y)| {
+            usize::try_from(y).ok()
+                .and_then(|y| rows.get(y))
+                .and_then(|row| usize::try_from(x).ok().and_then(|x| row.as_bytes().get(x)))
+                .is_some_and(|tile| *tile != b'#')
+        }
+    }
+
+    #[test]
+    fn straight_path() {
+        let map = ["....."];
+        let path = astar(walkable(&map), (0, 0), (4, 0));
+        assert_eq!(path, Some(vec![(0, 0), (1, 0), (2, 0), (3, 0), (4, 0)]));
+    }
+
+    #[test]
+    fn path_around_wall() {
+        let map = [
+            "...",
+            ".#.",
+            ".#.",
+        ];
+        let path = astar(walkable(&map), (0, 2), (2, 2)).expect("goal should be reachable");
+        assert_eq!(path.len(), 7);
+        assert_eq!(path.first(), Some(&(0, 2)));
+        assert_eq!(path.last(), Some(&(2, 2)));
+        for step in path.windows(2) {
+            let [(x0, y0), (x1, y1)] = [step[0], step[1]];
+            assert_eq!((x1 - x0).abs() + (y1 - y0).abs(), 1, "steps should be to a neighboring tile");
+            assert!(walkable(&map)((x1, y1)), "path should not go through walls");
+        }
+    }
+
+    #[test]
+    fn unreachable_goal() {
+        let map = [
+            "..#..",
+            "..#..",
+        ];
+        assert_eq!(astar(walkable(&map), (0, 0), (4, 1)), None);
+    }
+
+    #[test]
+    fn blocked_goal() {
+        let map = ["..#"];
+        assert_eq!(astar(walkable(&map), (0, 0), (2, 0)), None);
+    }
+
+    #[test]
+    fn start_is_goal() {
+        let map = ["..."];
+        assert_eq!(astar(walkable(&map), (1, 0), (1, 0)), Some(vec![(1, 0)]));
+    }
+}
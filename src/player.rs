@@ -0,0 +1,383 @@
+use std::f32::consts::{PI, TAU};
+use crate::level::Level;
+use crate::sprites::ItemKind;
+use ggez::glam::{vec2, Vec2, Mat2};
+
+// Gameplay parameters
+pub const COLLISION_RADIUS: f32 = 0.2; // In units
+const COLLISION_EPSILON: f32 = 0.0001; // How far from touching a wall the player is stopped, so they never count as inside it
+const MAX_PITCH: f32 = 0.4; // As a fraction of screen height
+const BOB_AMPLITUDE: f32 = 0.01; // As a fraction of screen height
+const BOB_FREQUENCY: f32 = 2.0; // In bobs / second
+const LIFT_SPEED: f32 = 0.5; // How quickly lifts raise and lower the player, in wall heights / second
+pub const PLAYER_MAX_HEALTH: f32 = 100.0;
+const DAMAGE_COOLDOWN: f32 = 0.8; // How long the player can't be hurt again after taking damage, in seconds
+const ACCELERATION: f32 = 20.0; // How quickly the player reaches walking speed, in units / second²
+const FRICTION: f32 = 12.0; // How quickly the player stops once no movement keys are held, in units / second²
+
+// Rendering parameters
+pub const CAMERA_HEIGHT: f32 = 0.5; // When standing on the floor, as a fraction of wall height
+const HORIZON_HEIGHT: f32 = 0.5; // As a fraction of screen height
+const ZOOM_SPEED: f32 = 12.0; // How quickly the view eases in and out of zoom, in 1 / seconds
+
+pub struct Player {
+    pub position: Vec2,
+    pub velocity: Vec2, // In units / second
+    pub direction: Vec2,
+    pub camera: Vec2,
+    pitch: f32, // How far the horizon is shifted down, as a fraction of screen height
+    pub bob_phase: f32, // In radians, advanced while walking
+    pub rays_dirty: bool, // Set when the camera turns or zooms so the cached ray directions get rebuilt
+    pub inventory: Inventory,
+    pub health: f32, // Dead at zero
+    pub damage_cooldown: f32, // Seconds left until the player can be hurt again
+    pub teleport_arrival: Option<(i32, i32)>, // The tile last teleported to, whose teleporter stays off until the player steps off it
+    pub camera_height: f32, // As a fraction of wall height, raised by lifts
+}
+
+/// What the player has picked up
+#[derive(Default)]
+pub struct Inventory {
+    pub coins: u32,
+    pub keys: Vec<u32>, // Ids of the keys held
+}
+
+impl Inventory {
+    pub fn add(&mut self, kind: ItemKind) {
+        match kind {
+            ItemKind::Coin => self.coins += 1,
+            ItemKind::Key(id) => self.keys.push(id),
+        }
+    }
+}
+
+impl Player {
+    pub fn new(position: Vec2, direction: Vec2, camera_plane_length: f32) -> Player {
+        let direction = direction.normalize(); // Make sure it's normalized!!
+        Player {
+            position,
+            velocity: Vec2::ZERO,
+            direction,
+            camera: vec2(direction.y, -direction.x) * camera_plane_length,
+            pitch: 0.0,
+            bob_phase: 0.0,
+            rays_dirty: true,
+            inventory: Inventory::default(),
+            health: PLAYER_MAX_HEALTH,
+            damage_cooldown: 0.0,
+            camera_height: CAMERA_HEIGHT,
+            teleport_arrival: None,
+        }
+    }
+
+    pub fn is_dead(&self) -> bool {
+        self.health <= 0.0
+    }
+
+    /// Moves the camera towards `target_height` at `LIFT_SPEED`
+    pub fn rise_towards(&mut self, target_height: f32, delta: f32) {
+        let step = LIFT_SPEED * delta;
+        self.camera_height += (target_height - self.camera_height).clamp(-step, step);
+    }
+
+    /// Loses `amount` health, unless the player was hurt too recently. Returns whether the damage was taken.
+    pub fn take_damage(&mut self, amount: f32) -> bool {
+        if self.damage_cooldown > 0.0 || self.is_dead() {
+            return false;
+        }
+        self.health = (self.health - amount).max(0.0);
+        self.damage_cooldown = DAMAGE_COOLDOWN;
+        true
+    }
+
+    pub fn rotate(&mut self, angle: f32) {
+        let rotation_matrix = Mat2::from_cols_array(&[angle.cos(), angle.sin(), -angle.sin(), angle.cos()]);
+        self.direction = rotation_matrix.mul_vec2(self.direction);
+        self.camera = rotation_matrix.mul_vec2(self.camera);
+        self.rays_dirty = true;
+    }
+
+    /// Fills in the direction of the ray for every screenspace x, spread evenly across the camera plane
+    pub fn aim_rays(&self, ray_directions: &mut [Vec2], x_resolution: f32) {
+        for (x, ray_direction) in ray_directions.iter_mut().enumerate() {
+            let camera_x = 2.0 * x as f32 / x_resolution - 1.0;
+            *ray_direction = self.direction + self.camera * camera_x;
+        }
+    }
+
+    /// Where a point in the world is relative to the camera, across the camera plane in x and in front of it in y
+    pub fn to_camera_space(&self, point: Vec2) -> Vec2 {
+        Mat2::from_cols(self.camera, self.direction).inverse().mul_vec2(point - self.position)
+    }
+
+    /// The field of view, encoded as the length of the camera plane
+    pub fn fov(&self) -> f32 {
+        self.camera.length()
+    }
+
+    /// Sets the field of view, rebuilding the camera plane perpendicular to the view direction
+    pub fn set_fov(&mut self, fov: f32) {
+        self.camera = vec2(self.direction.y, -self.direction.x) * fov;
+        self.rays_dirty = true;
+    }
+
+    /// Eases the field of view towards `target_fov`, snapping to it once close enough
+    pub fn zoom_towards(&mut self, target_fov: f32, delta: f32) {
+        let fov = self.fov();
+        if fov == target_fov {
+            return;
+        }
+        let eased = fov + (target_fov - fov) * (ZOOM_SPEED * delta).min(1.0);
+        match (target_fov - eased).abs() < 0.001 {
+            true => self.set_fov(target_fov),
+            false => self.set_fov(eased),
+        }
+    }
+
+    /// How many pixels one unit spans at a distance of one unit, horizontally and vertically alike.
+    /// Deriving it from the camera plane keeps pixels square, also while zooming.
+    pub fn focal_length(&self, x_resolution: f32) -> f32 {
+        x_resolution / (2.0 * self.fov())
+    }
+
+    /// Looks up (positive) or down (negative) by shifting the horizon
+    pub fn tilt(&mut self, amount: f32) {
+        self.pitch = (self.pitch + amount).clamp(-MAX_PITCH, MAX_PITCH);
+    }
+
+    /// The screen-space y coordinate of the horizon
+    pub fn horizon(&self, y_resolution: f32) -> f32 {
+        y_resolution * (HORIZON_HEIGHT + self.pitch + self.view_offset())
+    }
+
+    /// Advances the view bob, letting it settle back to level once the player stops walking.
+    /// Returns true if a foot came down, which happens at the bottom of every half-bob.
+    pub fn advance_bob(&mut self, delta: f32, walking: bool) -> bool {
+        let previous_step = (self.bob_phase / PI).floor();
+        let next_phase = self.bob_phase + delta * BOB_FREQUENCY * TAU;
+        if walking {
+            self.bob_phase = next_phase % TAU;
+        }
+        else {
+            // Finish the current half-bob so the view comes to rest at exactly zero offset
+            let rest_phase = (self.bob_phase / PI).ceil() * PI;
+            self.bob_phase = next_phase.min(rest_phase);
+        }
+        (self.bob_phase / PI).floor() != previous_step
+    }
+
+    /// The vertical view bob offset, as a fraction of screen height
+    fn view_offset(&self) -> f32 {
+        BOB_AMPLITUDE * self.bob_phase.sin()
+    }
+
+    /// Changes the velocity towards `target_velocity`, speeding up with `ACCELERATION`
+    /// or slowing down to a stop with `FRICTION` if the target is standing still
+    pub fn accelerate_towards(&mut self, target_velocity: Vec2, delta: f32) {
+        let rate = match target_velocity == Vec2::ZERO {
+            true => FRICTION,
+            false => ACCELERATION,
+        };
+        self.velocity += (target_velocity - self.velocity).clamp_length_max(rate * delta);
+    }
+
+    /// Moves the player by `offset` one axis at a time, so they slide along walls instead of sticking to them.
+    /// A blocked axis stops the player flush against the wall, `COLLISION_RADIUS` from it no matter how fast
+    /// they were going, and drops their velocity along it.
+    /// Returns whether the move was blocked along either axis
+    pub fn try_move(&mut self, offset: Vec2, level: &Level) -> bool {
+        let moved_x = vec2(self.position.x + offset.x, self.position.y);
+        let blocked_x = offset.x != 0.0 && level.collides(moved_x, COLLISION_RADIUS);
+        match blocked_x {
+            true => {
+                self.velocity.x = 0.0;
+                let flush = vec2(flush_against_wall(self.position.x, offset.x, COLLISION_RADIUS), self.position.y);
+                if !level.collides(flush, COLLISION_RADIUS) {
+                    self.position = flush;
+                }
+            }
+            false => self.position = moved_x,
+        }
+        let moved_y = vec2(self.position.x, self.position.y + offset.y);
+        let blocked_y = offset.y != 0.0 && level.collides(moved_y, COLLISION_RADIUS);
+        match blocked_y {
+            true => {
+                self.velocity.y = 0.0;
+                let flush = vec2(self.position.x, flush_against_wall(self.position.y, offset.y, COLLISION_RADIUS));
+                if !level.collides(flush, COLLISION_RADIUS) {
+                    self.position = flush;
+                }
+            }
+            false => self.position = moved_y,
+        }
+        blocked_x || blocked_y
+    }
+
+    /// Moves the player to the other end of the teleporter they're standing on, keeping their facing.
+    /// Arriving doesn't send them straight back, as the destination's teleporter stays off until they step off it.
+    /// Returns whether the player teleported
+    pub fn use_teleporters(&mut self, level: &Level) -> bool {
+        let tile = (self.position.x.floor() as i32, self.position.y.floor() as i32);
+        if self.teleport_arrival == Some(tile) {
+            return false;
+        }
+        self.teleport_arrival = None;
+        let Some(teleporter) = level.teleporter_at(tile.0, tile.1) else {
+            return false;
+        };
+        let (x, y) = teleporter.destination;
+        // Keep where the player stood within the tile, so walking through doesn't snap them to the middle
+        self.position = vec2(x as f32, y as f32) + self.position.fract();
+        self.teleport_arrival = Some((x, y));
+        true
+    }
+}
+
+/// Where a body of the given radius stops along one axis when moving from `from` by `offset` runs it into a wall:
+/// just short of the edge of the tile its leading side crossed into. Never moves the body backwards.
+fn flush_against_wall(from: f32, offset: f32, radius: f32) -> f32 {
+    match offset > 0.0 {
+        true => ((from + offset + radius).floor() - radius - COLLISION_EPSILON).max(from),
+        false => ((from + offset - radius).ceil() + radius + COLLISION_EPSILON).min(from),
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use crate::config::Config;
+    use crate::state::MAX_DELTA;
+    use crate::level::tests::level;
+
+    pub(crate) fn player(position: Vec2) -> Player {
+        Player::new(position, vec2(1.0, 0.0), 0.66)
+    }
+
+    #[test]
+    fn walking_off_an_open_map_is_blocked() {
+        let level = level(&["...", "...", "..."]);
+        let mut player = player(vec2(1.5, 1.5));
+        for _ in 0..100 {
+            player.try_move(vec2(0.1, -0.1), &level);
+        }
+        assert!(player.position.x <= 3.0 - COLLISION_RADIUS);
+        assert!(player.position.y >= COLLISION_RADIUS);
+    }
+
+    #[test]
+    fn walking_into_an_inside_corner_stops_at_both_walls() {
+        let level = level(&["SSSS", "S..S", "S..S", "SSSS"]);
+        let mut player = player(vec2(1.5, 1.5));
+        for _ in 0..20 {
+            player.try_move(vec2(-0.05, -0.05), &level);
+        }
+        // Stopped by the last step that would have overlapped the walls
+        let stopped = 1.0 + COLLISION_RADIUS..1.0 + COLLISION_RADIUS + 0.05;
+        assert!(stopped.contains(&player.position.x));
+        assert!(stopped.contains(&player.position.y));
+    }
+
+    #[test]
+    fn walls_touching_at_a_corner_cannot_be_slipped_between() {
+        let level = level(&["SSSS", "S.SS", "SS.S", "SSSS"]);
+        let mut player = player(vec2(1.5, 1.5));
+        for _ in 0..20 {
+            player.try_move(vec2(0.05, 0.05), &level);
+        }
+        assert!(player.position.x < 2.0 - COLLISION_RADIUS);
+        assert!(player.position.y < 2.0 - COLLISION_RADIUS);
+    }
+
+    #[test]
+    fn walking_along_a_wall_slides_instead_of_sticking() {
+        let level = level(&["SSSSS", "S...S", "S...S", "S...S", "SSSSS"]);
+        let mut player = Player::new(vec2(1.3, 1.5), vec2(-1.0, 1.0), 0.66);
+        player.velocity = vec2(-1.0, 1.0);
+        assert!(player.try_move(vec2(-0.2, 0.5), &level));
+        assert!((player.position.x - (1.0 + COLLISION_RADIUS)).abs() < 0.001);
+        assert_eq!(player.position.y, 2.0);
+        // Only the blocked axis loses its velocity
+        assert_eq!(player.velocity, vec2(0.0, 1.0));
+    }
+
+    #[test]
+    fn perspective_stays_square_at_any_aspect_ratio() {
+        for (x_resolution, y_resolution) in [(640.0, 360.0), (640.0, 480.0), (400.0, 400.0), (1920.0, 800.0)] {
+            let config = Config { x_resolution, y_resolution, ..Config::default() };
+            let mut player = player(vec2(0.0, 0.0));
+            player.set_fov(config.camera_plane_length());
+            // A unit spans as many pixels across as it does up and down
+            let focal_length = player.focal_length(x_resolution);
+            assert!((focal_length - y_resolution / (2.0 * config.field_of_view)).abs() < 0.001);
+            // So a ray 45 degrees off the view direction lands one focal length to the right of the middle of the screen
+            let column = x_resolution / 2.0 + focal_length;
+            let camera_x = 2.0 * column / x_resolution - 1.0;
+            let ray = player.direction + player.camera * camera_x;
+            assert!((ray.angle_between(player.direction).abs() - PI / 4.0).abs() < 0.001, "{x_resolution}x{y_resolution}");
+        }
+    }
+
+    #[test]
+    fn hitches_cannot_carry_the_player_through_walls() {
+        // Without the cap, one step would land far beyond the wall in the open room behind it
+        let room = format!("S.S{}S", ".".repeat(40));
+        let level = level(&[&"S".repeat(44), &room, &"S".repeat(44)]);
+        let config = Config::default();
+        let mut player = player(vec2(1.5, 1.5));
+        // Walking into a wall through a ten second hitch, with the frame time capped like `update` does
+        let step = config.move_speed * 10.0_f32.min(MAX_DELTA);
+        for _ in 0..100 {
+            player.try_move(vec2(step, 0.0), &level);
+        }
+        assert!(player.position.x <= 2.0 - COLLISION_RADIUS);
+    }
+
+    #[test]
+    fn players_stop_the_collision_radius_from_walls_on_every_side() {
+        let level = level(&["SSS", "S.S", "SSS"]);
+        let cases = [
+            (vec2(0.3, 0.0), vec2(2.0 - COLLISION_RADIUS, 1.5)),
+            (vec2(-0.3, 0.0), vec2(1.0 + COLLISION_RADIUS, 1.5)),
+            (vec2(0.0, 0.3), vec2(1.5, 2.0 - COLLISION_RADIUS)),
+            (vec2(0.0, -0.3), vec2(1.5, 1.0 + COLLISION_RADIUS)),
+        ];
+        for (offset, expected) in cases {
+            // Starting off right next to the wall, so a single step would cross into it
+            let mut player = Player::new(vec2(1.5, 1.5) + offset.normalize() * 0.25, vec2(1.0, 0.0), 0.66);
+            player.velocity = offset;
+            assert!(player.try_move(offset, &level));
+            assert!(player.position.distance(expected) < 0.001, "moving by {offset} ended at {}", player.position);
+            assert!(!level.collides(player.position, COLLISION_RADIUS));
+            assert_eq!(player.velocity, Vec2::ZERO);
+        }
+    }
+
+    #[test]
+    fn flush_against_wall_stops_just_short_of_the_tile_edge() {
+        assert!((flush_against_wall(1.5, 0.6, 0.2) - 1.8).abs() < 0.001);
+        assert!(flush_against_wall(1.5, 0.6, 0.2) < 1.8);
+        assert!((flush_against_wall(1.5, -0.6, 0.2) - 1.2).abs() < 0.001);
+        assert!(flush_against_wall(1.5, -0.6, 0.2) > 1.2);
+        // Never backwards, even when already closer than the radius
+        assert_eq!(flush_against_wall(1.9, 0.3, 0.2), 1.9);
+        assert_eq!(flush_against_wall(1.1, -0.3, 0.2), 1.1);
+    }
+
+    #[test]
+    fn rays_spread_across_the_camera_plane() {
+        let player = Player::new(vec2(1.5, 1.5), vec2(1.0, 0.0), 0.66);
+        let mut ray_directions = [Vec2::ZERO; 4];
+        player.aim_rays(&mut ray_directions, 4.0);
+        // The leftmost column looks along the edge of the camera plane and every column moves a quarter of it across
+        assert_eq!(ray_directions, [vec2(1.0, 0.66), vec2(1.0, 0.33), vec2(1.0, 0.0), vec2(1.0, -0.33)]);
+    }
+
+    #[test]
+    fn camera_space_is_across_and_into_the_view() {
+        let player = Player::new(vec2(1.5, 1.5), vec2(1.0, 0.0), 0.66);
+        assert!(player.to_camera_space(vec2(3.5, 1.5)).abs_diff_eq(vec2(0.0, 2.0), 1e-6));
+        // Across is measured in camera plane lengths, so points on the right edge of the view are as far across as they are deep
+        assert!(player.to_camera_space(vec2(3.5, 1.5 - 1.32)).abs_diff_eq(vec2(2.0, 2.0), 1e-6));
+        assert!(player.to_camera_space(vec2(0.5, 1.5)).y < 0.0);
+    }
+}
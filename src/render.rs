@@ -0,0 +1,475 @@
+use std::io::Read;
+use crate::config::{Config, SkyLayer};
+use crate::level::{Level, RayHit, Side, RAY_EPSILON, layer_at};
+use crate::player::Player;
+use serde::Deserialize;
+use ggez::{
+    graphics::{Color, Image, ImageFormat, DrawParam, Rect, InstanceArray},
+    glam::{vec2, Vec2},
+    Context,
+    GameError,
+};
+
+// Rendering parameters
+const SIDE_SHADING: f32 = 0.7; // Brightness of north/south facing walls relative to east/west ones
+const NEAR_CLIP: f32 = 0.05; // Walls closer than this are drawn as if they were this far away, in units. Keeps close walls from scaling up without bound
+const FLASHLIGHT_RADIUS: f32 = 0.4; // How far from the center of the view the flashlight beam fades out, as a fraction of screen height
+const FLASHLIGHT_RANGE: f32 = 8.0; // How far the flashlight reaches before fading out, in units
+const FLASHLIGHT_AMBIENT: f32 = 0.5; // Brightness of everything outside the beam while the flashlight is on
+const LIQUID_SHIMMER_SPEED: f32 = 2.0; // How quickly liquid surfaces shimmer, in radians / second
+const LIQUID_RIPPLES: f32 = 3.0; // How tightly the shimmer ripples across liquid surfaces, in radians / unit
+
+// Misc parameters
+const TEXTURE_MANIFEST_PATH: &str = "/textures.toml"; // In the resources directory
+const MISSING_TEXTURE_CHECK_SIZE: u32 = 8; // In texture pixels
+
+/// Lists the textures available to levels, loaded from the resources directory
+#[derive(Deserialize)]
+pub struct TextureManifest {
+    walls: Vec<ManifestTexture>, // Indexed by a tile's texture
+    // Textures the floor and ceiling layers of a map can pick from, starting at index 1.
+    // Index 0 is always the level's default floor or ceiling texture.
+    #[serde(default)]
+    pub flats: Vec<String>,
+}
+
+/// A wall texture as listed in the manifest, either the path of a single image
+/// or a table of frame paths and the rate to cycle through them at
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum ManifestTexture {
+    Static(String),
+    Animated {
+        frames: Vec<String>,
+        fps: f32,
+    },
+}
+
+impl TextureManifest {
+    pub fn load(ctx: &Context) -> Result<TextureManifest, GameError> {
+        let mut manifest_str = String::new();
+        ctx.fs.open(TEXTURE_MANIFEST_PATH)?
+            .read_to_string(&mut manifest_str)
+            .map_err(|error| GameError::ResourceLoadError(format!("Failed reading {TEXTURE_MANIFEST_PATH}: {error}")))?;
+        toml::from_str(&manifest_str)
+            .map_err(|error| GameError::ResourceLoadError(format!("Failed parsing {TEXTURE_MANIFEST_PATH}: {error}")))
+    }
+
+    pub fn load_walls(&self, ctx: &Context) -> Result<Vec<WallTexture>, GameError> {
+        self.walls
+            .iter()
+            .enumerate()
+            .map(|(index, texture)| {
+                let load = |path: &String| Image::from_path(ctx, path)
+                    .map_err(|error| GameError::ResourceLoadError(format!(
+                        "Failed loading wall texture {index} ({path}) listed in {TEXTURE_MANIFEST_PATH}: {error}"
+                    )));
+                match texture {
+                    ManifestTexture::Static(path) => load(path).map(WallTexture::Static),
+                    ManifestTexture::Animated { frames, .. } if frames.is_empty() => Err(GameError::ResourceLoadError(format!(
+                        "Animated wall texture {index} listed in {TEXTURE_MANIFEST_PATH} has no frames"
+                    ))),
+                    ManifestTexture::Animated { frames, fps } => Ok(WallTexture::Animated {
+                        frames: frames.iter().map(load).collect::<Result<Vec<Image>, GameError>>()?,
+                        fps: *fps,
+                    }),
+                }
+            })
+            .collect()
+    }
+}
+
+/// A wall texture, optionally cycling through several frames.
+/// An animated texture is declared in the texture manifest like
+/// `{ frames = ["/textures/water_0.png", "/textures/water_1.png", "/textures/water_2.png"], fps = 4.0 }`
+pub enum WallTexture {
+    Static(Image),
+    Animated {
+        frames: Vec<Image>,
+        fps: f32,
+    },
+}
+
+impl WallTexture {
+    pub fn frames(&self) -> &[Image] {
+        match self {
+            WallTexture::Static(image) => std::slice::from_ref(image),
+            WallTexture::Animated { frames, .. } => frames,
+        }
+    }
+
+    /// The index of the frame to draw `elapsed` seconds into the game
+    fn frame_index(&self, elapsed: f32) -> usize {
+        match self {
+            WallTexture::Static(_) => 0,
+            WallTexture::Animated { frames, fps } => (elapsed * fps) as usize % frames.len(),
+        }
+    }
+
+    /// The image to draw `elapsed` seconds into the game
+    pub fn frame(&self, elapsed: f32) -> &Image {
+        &self.frames()[self.frame_index(elapsed)]
+    }
+}
+
+pub struct Gfx {
+    pub wall_textures: Vec<WallTexture>,
+    pub missing_texture: WallTexture, // Drawn for any texture index past the end of `wall_textures`
+    pub wall_batches: Vec<Vec<InstanceArray>>, // One per frame of every wall texture, in the order of `wall_textures` with the missing texture last
+    pub floor_batches: Vec<InstanceArray>, // One per floor texture, the default one followed by the manifest's `flats`
+    pub ceiling_batches: Vec<InstanceArray>, // One per ceiling texture, the default one followed by the manifest's `flats`
+    pub liquid_batch: InstanceArray, // Tints over the floor pixels of liquid tiles, drawn with a single white pixel
+}
+
+impl Gfx {
+    pub fn wall_texture(&self, index: usize) -> &WallTexture {
+        self.wall_textures.get(index).unwrap_or(&self.missing_texture)
+    }
+
+    /// The batch of wall slices drawn with the given texture `elapsed` seconds into the game
+    pub fn wall_batch(&mut self, index: usize, elapsed: f32) -> &mut InstanceArray {
+        let index = index.min(self.wall_textures.len());
+        let frame = self.wall_texture(index).frame_index(elapsed);
+        &mut self.wall_batches[index][frame]
+    }
+}
+
+/// Builds a magenta and black checkerboard that's hard to mistake for a real texture
+pub fn missing_texture(ctx: &Context, size: u32) -> Image {
+    let pixels = (0..size * size)
+        .flat_map(|i| {
+            let (x, y) = (i % size, i / size);
+            if (x / MISSING_TEXTURE_CHECK_SIZE + y / MISSING_TEXTURE_CHECK_SIZE) % 2 == 0 {
+                [255, 0, 255, 255]
+            } else {
+                [0, 0, 0, 255]
+            }
+        })
+        .collect::<Vec<u8>>();
+    Image::from_pixels(ctx, &pixels, ImageFormat::Rgba8UnormSrgb, size, size)
+}
+
+/// How the floor half of the screen is rendered
+pub enum FloorMode {
+    /// Sample the floor texture per map cell
+    Tiled,
+    /// Sample a distant texture that only shifts by the config's `parallax_factor` of the player's movement
+    Parallax(Image),
+    /// Fill with the config's gradient, from the horizon color at the horizon to the zenith color at the bottom of the screen
+    Gradient,
+}
+
+/// How the ceiling half of the screen is rendered
+pub enum CeilingMode {
+    /// Sample the ceiling texture per map cell
+    Tiled,
+    /// Sample a distant texture that only shifts by the config's `parallax_factor` of the player's movement
+    Parallax(Image),
+    /// Draw a panoramic sky of one or more layers
+    Sky(Sky),
+    /// Fill with the config's gradient, from the horizon color at the horizon to the zenith color at the top of the screen
+    Gradient,
+}
+
+/// A panoramic sky made of layers drawn on top of each other, farthest first.
+/// Each layer comes with a scroll multiplier: at 1 the width of its image covers a full turn,
+/// and higher multipliers scroll nearer layers faster as the player turns, faking depth.
+/// Multipliers should be whole numbers so each layer lines up with itself after a full turn.
+pub struct Sky {
+    pub layers: Vec<(Image, f32)>,
+}
+
+impl Sky {
+    /// Loads the images of the sky layers set in the config
+    pub fn load(ctx: &Context, layers: &[SkyLayer]) -> Result<Sky, GameError> {
+        let layers = layers
+            .iter()
+            .map(|layer| Ok((Image::from_path(ctx, &layer.image)?, layer.scroll)))
+            .collect::<Result<Vec<(Image, f32)>, GameError>>()?;
+        Ok(Sky { layers })
+    }
+}
+
+impl FloorMode {
+    /// Where the floor texture is sampled from when standing at `position`
+    fn origin(&self, position: Vec2, parallax_factor: f32) -> Vec2 {
+        match self {
+            FloorMode::Tiled | FloorMode::Gradient => position,
+            FloorMode::Parallax(_) => position * parallax_factor,
+        }
+    }
+}
+
+impl CeilingMode {
+    /// Where the ceiling texture is sampled from when standing at `position`
+    fn origin(&self, position: Vec2, parallax_factor: f32) -> Vec2 {
+        match self {
+            CeilingMode::Tiled | CeilingMode::Sky(_) | CeilingMode::Gradient => position,
+            CeilingMode::Parallax(_) => position * parallax_factor,
+        }
+    }
+}
+
+/// A slice of wall in a single screen column, see [`cast_walls`]
+pub struct WallSlice {
+    pub column: usize,
+    pub texture_index: usize, // May be past the last texture
+    pub params: DrawParam,
+    pub span: (f32, f32), // The screen rows it covers, from top to bottom
+    pub batched: bool, // Whether it goes in the batch of its texture instead of being drawn on its own
+}
+
+/// How far away the nearest wall that hides sprites is in a column, infinite when every wall can be seen through
+pub fn sprite_depth(hits: &[RayHit]) -> f32 {
+    hits.iter().find(|hit| !hit.transparent).map_or(f32::INFINITY, |hit| hit.perpendicular_distance)
+}
+
+/// Turns the hits of every column into wall slices, passing them to `push` from back to front in each column
+pub fn cast_walls(level: &Level, config: &Config, player: &Player, ray_directions: &[Vec2], column_hits: &[Vec<RayHit>], flashlight_intensity: Option<f32>, mut push: impl FnMut(WallSlice)) {
+    let x_resolution = config.x_resolution;
+    let y_resolution = config.y_resolution;
+    let texture_size = config.texture_size as f32;
+    let pixel_frac = config.pixel_frac();
+    let horizon = player.horizon(y_resolution);
+    let focal_length = player.focal_length(x_resolution);
+    for (column, (hits, ray_direction)) in column_hits.iter().zip(ray_directions).enumerate() {
+        let x = column as f32; // Re-floatify x to enable use in graphics drawing
+        let ray_direction = *ray_direction;
+        // Sprites behind transparent walls still show, they're drawn before the walls in front of them
+        let hides_sprites = sprite_depth(hits).is_finite();
+        for (layer, hit) in hits.iter().enumerate() {
+            let side = hit.side;
+            let perpendicular_distance = hit.perpendicular_distance.max(NEAR_CLIP);
+            // Create draw params
+            let mut texture_x = hit.wall_x * texture_size;
+            if !hit.diagonal && ((side == Side::EastWest && ray_direction.x > 0.0)
+            || (side == Side::NorthSouth && ray_direction.y < 0.0)) {
+                texture_x = texture_size - texture_x - 1.0;
+            }
+            let brightness = match side {
+                Side::EastWest => 1.0,
+                Side::NorthSouth => SIDE_SHADING,
+            };
+            // Walls stand on the floor, so short walls show the bottom part of their texture
+            let full_height = focal_length / perpendicular_distance;
+            let bottom = horizon + full_height * player.camera_height;
+            let top = bottom - full_height * hit.height;
+            let src_height = hit.height.min(1.0);
+            let hit_point = player.position + ray_direction * hit.perpendicular_distance;
+            // Wall faces take the painted brightness of the open tile they face
+            let facing_tile = hit_point - ray_direction.normalize() * RAY_EPSILON;
+            let painted = level.light_map_at(facing_tile.x.floor() as i32, facing_tile.y.floor() as i32);
+            let mut color = dim(config.fog(perpendicular_distance), brightness * painted);
+            if !level.lights.is_empty() {
+                color = add_light(color, level.light_at(hit_point), brightness * config.brightness);
+            }
+            if let Some(intensity) = flashlight_intensity {
+                // Lit by the part of the column closest to the center of the beam
+                let beam_offset = vec2(x - x_resolution / 2.0, horizon.clamp(top, bottom) - horizon) / y_resolution;
+                color = dim(color, flashlight(beam_offset, hit.perpendicular_distance, intensity));
+            }
+            let params = DrawParam::new()
+            .src(Rect::new(texture_x * pixel_frac, 1.0 - src_height, pixel_frac, src_height))
+            .dest(vec2(x, top))
+            .scale(vec2(1.0, (bottom - top) * pixel_frac / src_height))
+            .color(color)
+            .z(-(perpendicular_distance * 100.0) as i32);
+            // Once a column has a wall that hides sprites, its backmost wall is behind every sprite showing in it
+            // as well as every other wall, so it's batched and drawn under all of them.
+            // The walls in front of it keep a draw of their own, to be sorted in between the sprites.
+            let batched = layer + 1 == hits.len() && hides_sprites;
+            push(WallSlice { column, texture_index: hit.texture_index, params, span: (top, bottom), batched });
+        }
+    }
+}
+
+/// The batch a pixel of the floor or ceiling is drawn in, see [`cast_flats`]
+#[derive(Clone, Copy)]
+pub enum FlatBatch {
+    Floor(usize), // By texture index, which may be past the last texture
+    Ceiling(usize),
+    Liquid, // Tints over the floor
+}
+
+/// Casts the floor and ceiling one screen row at a time, passing every pixel that shows to `push`
+/// along with the batch it goes in. Pixels covered by the walls in `wall_spans` are skipped,
+/// `flashlight_intensity` lights up the center of the view when the flashlight is on, and `elapsed` ripples liquids.
+pub fn cast_flats(level: &Level, config: &Config, player: &Player, wall_spans: &[(f32, f32)], flashlight_intensity: Option<f32>, elapsed: f32, mut push: impl FnMut(FlatBatch, DrawParam)) {
+    let x_resolution = config.x_resolution;
+    let y_resolution = config.y_resolution;
+    let pixel_frac = config.pixel_frac();
+    let horizon = player.horizon(y_resolution);
+    let focal_length = player.focal_length(x_resolution);
+    let ray_left = player.direction - player.camera;
+    let ray_right = player.direction + player.camera;
+    for y in 0..(y_resolution as u32) {
+        let y = y as f32;
+        // Rows below the horizon are floor, rows above it are ceiling
+        let is_floor = y >= horizon;
+        let (horizon_distance, camera_height, origin) = match is_floor {
+            true => (y + 1.0 - horizon, focal_length * player.camera_height, level.floor_mode.origin(player.position, config.parallax_factor)),
+            false => (horizon - y, focal_length * (1.0 - player.camera_height), level.ceiling_mode.origin(player.position, config.parallax_factor)),
+        };
+        let row_distance = camera_height / horizon_distance;
+        let row_fog = config.fog(row_distance);
+        let x_step = row_distance * (ray_right.x - ray_left.x) / x_resolution;
+        let y_step = row_distance * (ray_right.y - ray_left.y) / x_resolution;
+        let mut world_x = origin.x + row_distance * ray_left.x;
+        let mut world_y = origin.y + row_distance * ray_left.y;
+        // Parallax planes are a single distant texture, so they ignore the per-tile layers
+        let layer = match is_floor {
+            true => match level.floor_mode {
+                FloorMode::Tiled => Some(&level.floor_map),
+                FloorMode::Parallax(_) => None,
+                FloorMode::Gradient => continue,
+            },
+            false => match level.ceiling_mode {
+                CeilingMode::Tiled => Some(&level.ceiling_map),
+                CeilingMode::Parallax(_) => None,
+                // The sky and gradient are drawn separately instead of the ceiling
+                CeilingMode::Sky(_) | CeilingMode::Gradient => continue,
+            },
+        };
+        for x in 0..(x_resolution as u32) {
+            let cell_x = world_x.floor();
+            let cell_y = world_y.floor();
+            let texture_x = world_x - cell_x;
+            let texture_y = world_y - cell_y;
+            world_x += x_step;
+            world_y += y_step;
+            let (wall_top, wall_bottom) = wall_spans[x as usize];
+            if wall_top < y && y < wall_bottom - 1.0 {
+                continue;
+            }
+            if !is_floor && layer.is_some() && !level.has_ceiling(cell_x as i32, cell_y as i32) {
+                continue;
+            }
+            let x = x as f32;
+            let texture_index = match layer {
+                Some(layer) => layer_at(layer, cell_x as i32, cell_y as i32),
+                None => 0,
+            };
+            // Lit per tile, so lights shine on whole cells like the floor and ceiling layers
+            let color = match layer.is_some() {
+                true => {
+                    let painted = dim(row_fog, level.light_map_at(cell_x as i32, cell_y as i32));
+                    match level.lights.is_empty() {
+                        true => painted,
+                        false => add_light(painted, level.light_at(vec2(cell_x + 0.5, cell_y + 0.5)), config.brightness),
+                    }
+                }
+                false => row_fog,
+            };
+            let color = match is_floor && config.ambient_occlusion {
+                true => dim(color, level.occlusion_at(cell_x as i32, cell_y as i32, vec2(texture_x, texture_y))),
+                false => color,
+            };
+            let color = match flashlight_intensity {
+                Some(intensity) => {
+                    let beam_offset = vec2(x - x_resolution / 2.0, y - horizon) / y_resolution;
+                    dim(color, flashlight(beam_offset, row_distance, intensity))
+                }
+                None => color,
+            };
+            let src_rect = Rect::new(texture_x, texture_y, pixel_frac, pixel_frac);
+            let batch = match is_floor {
+                true => FlatBatch::Floor(texture_index),
+                false => FlatBatch::Ceiling(texture_index),
+            };
+            push(batch, DrawParam::new().src(src_rect).dest(vec2(x, y)).color(color));
+            let liquid = match is_floor && layer.is_some() {
+                true => level.liquid_at(cell_x as i32, cell_y as i32).and_then(|liquid| config.liquids.get(&liquid)),
+                false => None,
+            };
+            if let Some(liquid) = liquid {
+                let ripple = (elapsed * LIQUID_SHIMMER_SPEED + (cell_x + texture_x + cell_y + texture_y) * LIQUID_RIPPLES).sin();
+                let opacity = (liquid.opacity * (1.0 + liquid.shimmer * ripple)).clamp(0.0, 1.0);
+                // Lit and fogged like the floor under it. Premultiplied, as the batch is drawn along with the sprites
+                let [r, g, b] = liquid.color;
+                let tint = Color::new(r * color.r * opacity, g * color.g * opacity, b * color.b * opacity, opacity);
+                push(FlatBatch::Liquid, DrawParam::new().dest(vec2(x, y)).color(tint));
+            }
+        }
+    }
+}
+
+/// Sorts `items` from farthest from `viewer` to nearest, by the position `position` gives for each
+pub fn sort_far_to_near<T>(items: &mut [T], viewer: Vec2, position: impl Fn(&T) -> Vec2) {
+    items.sort_by(|a, b| {
+        let a_distance = (position(a) - viewer).length_squared();
+        let b_distance = (position(b) - viewer).length_squared();
+        b_distance.total_cmp(&a_distance)
+    });
+}
+
+/// How brightly the flashlight lights something `distance` units away, seen `beam_offset` from the center of the view
+/// in fractions of screen height, as a factor to multiply its color with.
+/// Fades smoothly towards the edge of the beam and with distance, down to `FLASHLIGHT_AMBIENT` outside it.
+pub fn flashlight(beam_offset: Vec2, distance: f32, intensity: f32) -> f32 {
+    let beam = (1.0 - beam_offset.length_squared() / (FLASHLIGHT_RADIUS * FLASHLIGHT_RADIUS)).max(0.0).powi(2);
+    let reach = (1.0 - distance / FLASHLIGHT_RANGE).max(0.0);
+    FLASHLIGHT_AMBIENT + intensity * beam * reach
+}
+
+/// Adds `light` scaled by `factor` to the RGB channels of a color, leaving alpha untouched
+fn add_light(color: Color, light: [f32; 3], factor: f32) -> Color {
+    let [r, g, b] = light.map(|channel| channel * factor);
+    Color::new(color.r + r, color.g + g, color.b + b, color.a)
+}
+
+/// Works out the scale and offset that fit a frame of `resolution` centered in a window of `window_size`.
+/// Frames are scaled up by whole multiples so pixels stay square, letterboxing the rest,
+/// and only shrunk smoothly when the window is smaller than the frame.
+/// Returns `None` for a window without any area, like a minimized one.
+pub fn place_frame(window_size: Vec2, resolution: Vec2) -> Option<(f32, Vec2)> {
+    if window_size.x <= 0.0 || window_size.y <= 0.0 {
+        return None;
+    }
+    let fit = (window_size.x / resolution.x).min(window_size.y / resolution.y);
+    let scale = match fit >= 1.0 {
+        true => fit.floor(),
+        false => fit,
+    };
+    let offset = ((window_size - resolution * scale) / 2.0).floor();
+    Some((scale, offset))
+}
+
+/// Scales the RGB channels of a color by `factor`, leaving alpha untouched
+pub fn dim(color: Color, factor: f32) -> Color {
+    Color::new(color.r * factor, color.g * factor, color.b * factor, color.a)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn manifests_list_static_and_animated_textures() {
+        let manifest: TextureManifest = toml::from_str(r#"
+            walls = ["/textures/stone.png", { frames = ["/textures/water_0.png", "/textures/water_1.png"], fps = 4.0 }]
+        "#).unwrap();
+        assert!(matches!(&manifest.walls[0], ManifestTexture::Static(path) if path == "/textures/stone.png"));
+        assert!(matches!(&manifest.walls[1], ManifestTexture::Animated { frames, fps } if frames.len() == 2 && *fps == 4.0));
+        // Levels can do without extra floor and ceiling textures
+        assert!(manifest.flats.is_empty());
+    }
+
+    #[test]
+    fn manifests_list_flat_textures() {
+        let manifest: TextureManifest = toml::from_str(r#"
+            walls = ["/textures/stone.png"]
+            flats = ["/textures/brick.png", "/textures/wood.png"]
+        "#).unwrap();
+        assert_eq!(manifest.flats, vec!["/textures/brick.png", "/textures/wood.png"]);
+    }
+
+    #[test]
+    fn sprites_sort_far_to_near() {
+        let mut positions = vec![vec2(2.0, 1.0), vec2(9.0, 1.0), vec2(1.0, 5.0), vec2(1.5, 1.0)];
+        sort_far_to_near(&mut positions, vec2(1.0, 1.0), |position| *position);
+        assert_eq!(positions, vec![vec2(9.0, 1.0), vec2(1.0, 5.0), vec2(2.0, 1.0), vec2(1.5, 1.0)]);
+        // Which is the other way around from another viewpoint
+        sort_far_to_near(&mut positions, vec2(9.0, 1.0), |position| *position);
+        assert_eq!(positions, vec![vec2(1.0, 5.0), vec2(1.5, 1.0), vec2(2.0, 1.0), vec2(9.0, 1.0)]);
+    }
+}
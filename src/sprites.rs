@@ -0,0 +1,472 @@
+use std::io::Read;
+use std::f32::consts::TAU;
+use crate::audio::AmbientSound;
+use crate::config::Config;
+use crate::level::{Level, has_line_of_sight};
+use crate::player::{Player, CAMERA_HEIGHT, COLLISION_RADIUS};
+use crate::render::{dim, missing_texture};
+use ggez::{
+    graphics::{Color, Image, ImageFormat, DrawParam, Rect, Canvas},
+    glam::{vec2, Vec2},
+    Context,
+    GameError,
+};
+
+// Gameplay parameters
+const ENEMY_SIGHT_DISTANCE: f32 = 10.0; // How far enemies can spot the player from, in units
+const ENEMY_SPEED: f32 = 1.5; // In units / second
+const ENEMY_HEALTH: u32 = 3; // How many shots it takes to kill an enemy
+const ENEMY_HURT_DURATION: f32 = 0.3; // How long enemies flinch after being shot, in seconds
+const ENEMY_REPATH_INTERVAL: f32 = 0.5; // How often chasing enemies look for a new path when neither end has moved, in seconds
+const PARTICLE_GRAVITY: f32 = 4.0; // How quickly particles fall, in units / second²
+const PARTICLE_LIFETIME: f32 = 0.6; // How long particles last, shrinking away as they age, in seconds
+
+// Rendering parameters
+const WEAPON_HEIGHT: f32 = 0.5; // How tall the weapon is drawn, as a fraction of screen height
+const WEAPON_SWAY: [f32; 2] = [0.03, 0.02]; // How far the weapon bobs sideways and down while walking, as a fraction of screen height
+const PARTICLE_SIZE: f32 = 6.0; // How tall a new particle is at a distance of one unit, in pixels
+
+// Misc parameters
+const COLOR_KEY: Option<[u8; 3]> = Some([255, 0, 255]); // Sprite pixels of this color are made transparent, for sprites without an alpha channel. None turns color keying off
+// Enemies are placed with `e` in the enemies layer of a map.
+// Their sprites are named `<state>_<direction>_<frame>.png`, where direction 0 shows the front,
+// 2 the enemy's right side, 4 its back and 6 its left side
+const ENEMY_SPRITE_DIRECTORY: &str = "/enemy";
+const ENEMY_DIRECTIONS: usize = 8;
+const ENEMY_FRAME_COUNT: usize = 2; // Animation frames per state and direction
+const ENEMY_FPS: f32 = 4.0;
+const ENEMY_SCALE: f32 = 4.0;
+const WEAPON_FRAME_COUNT: usize = 4;
+const WEAPON_FPS: f32 = 16.0;
+
+/// Loads a sprite with its colors premultiplied by alpha, to be drawn with `BlendMode::PREMULTIPLIED`.
+/// With straight alpha, filtering blends the color of the transparent pixels around a sprite into its soft edges,
+/// which shows up as a dark fringe. Pixels of the `COLOR_KEY` color are made fully transparent first.
+fn load_sprite(ctx: &Context, path: &str) -> Result<Image, GameError> {
+    let mut encoded = Vec::new();
+    ctx.fs.open(path)?.read_to_end(&mut encoded)?;
+    let mut pixels = image::load_from_memory(&encoded)
+        .map_err(|error| GameError::ResourceLoadError(format!("Failed decoding sprite {path}: {error}")))?
+        .to_rgba8();
+    for pixel in pixels.pixels_mut() {
+        let [r, g, b, a] = pixel.0;
+        if COLOR_KEY == Some([r, g, b]) {
+            pixel.0 = [0, 0, 0, 0];
+            continue;
+        }
+        let alpha = a as f32 / 255.0;
+        pixel.0 = [premultiply(r, alpha), premultiply(g, alpha), premultiply(b, alpha), a];
+    }
+    Ok(Image::from_pixels(ctx, &pixels, ImageFormat::Rgba8UnormSrgb, pixels.width(), pixels.height()))
+}
+
+/// Multiplies an sRGB encoded color channel by `alpha`.
+/// The multiplication happens in linear space, where the GPU blends, so edges don't come out darker than they should.
+fn premultiply(channel: u8, alpha: f32) -> u8 {
+    let encoded = channel as f32 / 255.0;
+    let linear = match encoded <= 0.04045 {
+        true => encoded / 12.92,
+        false => ((encoded + 0.055) / 1.055).powf(2.4),
+    } * alpha;
+    let encoded = match linear <= 0.0031308 {
+        true => linear * 12.92,
+        false => 1.055 * linear.powf(1.0 / 2.4) - 0.055,
+    };
+    (encoded * 255.0).round() as u8
+}
+
+pub trait Sprite {
+    /// The image to draw, as seen by a viewer at `viewer`
+    fn sprite(&self, viewer: Vec2) -> Image;
+    fn position(&self) -> Vec2;
+    fn scale(&self) -> f32;
+    /// How far above the horizon the sprite's center floats, in units
+    fn vertical_offset(&self) -> f32 {
+        0.0
+    }
+    /// The color the sprite is tinted with, before brightness
+    fn tint(&self) -> Color {
+        Color::WHITE
+    }
+
+    /// Draws the sprite as a billboard, hiding the columns where a wall is in front of it.
+    /// Returns whether any part of it was drawn.
+    fn draw(&self, canvas: &mut Canvas, player: &Player, config: &Config, z_buffer: &[f32]) -> bool {
+        let transformed_position = player.to_camera_space(self.position());
+        // Behind the camera
+        if transformed_position.y <= 0.0 {
+            return false;
+        }
+        let screen_x = (config.x_resolution / 2.0) * (1.0 + transformed_position.x / transformed_position.y);
+
+        // Sprite scales are relative to a view where one unit at a distance of one fills the screen height
+        let projection = player.focal_length(config.x_resolution) / config.y_resolution;
+        let scale = self.scale() * projection / transformed_position.y;
+        if scale <= 0.0 {
+            return false;
+        }
+        let sprite = self.sprite(player.position);
+        let width = sprite.width() as f32 * scale;
+        let height = sprite.height() as f32 * scale;
+        let left = screen_x - width / 2.0;
+        // Entirely off either side of the screen
+        if left + width < 0.0 || left > config.x_resolution {
+            return false;
+        }
+        // Scaled like the sprite itself, so it stays at the same height in the world as it gets closer
+        // Measured from eye level, which lifts move up and down
+        let eye_offset = player.camera_height - CAMERA_HEIGHT;
+        let offset = (self.vertical_offset() - eye_offset) * player.focal_length(config.x_resolution) / transformed_position.y;
+        let top = player.horizon(config.y_resolution) - offset - height / 2.0;
+        let first_column = left.floor().max(0.0) as usize;
+        let last_column = ((left + width).ceil().max(0.0) as usize).min(z_buffer.len());
+        // Draw each unbroken run of unoccluded columns as a single slice of the sprite
+        let mut span_start = None;
+        let mut drawn = false;
+        for column in first_column..=last_column {
+            let visible = column < last_column && z_buffer.get(column).is_some_and(|depth| transformed_position.y < *depth);
+            match (visible, span_start) {
+                (true, None) => span_start = Some(column),
+                (false, Some(start)) => {
+                    let start = start as f32;
+                    let param = DrawParam::new()
+                    .src(Rect::new((start - left) / width, 0.0, (column as f32 - start) / width, 1.0))
+                    .dest(Vec2::new(start, top))
+                    .scale(Vec2::new(scale, scale))
+                    .color(dim(self.tint(), config.brightness))
+                    .z(-(transformed_position.y * 100.0) as i32);
+                    canvas.draw(&sprite, param);
+                    span_start = None;
+                    drawn = true;
+                }
+                _ => (),
+            }
+        }
+        drawn
+    }
+}
+
+pub struct Decoration {
+    frames: Vec<Image>,
+    fps: f32,
+    timer: f32, // In seconds
+    pub position: Vec2,
+    scale: f32,
+    vertical_offset: f32, // In units, positive is up
+    sound: Option<AmbientSound>, // Loops for as long as the decoration is in the level
+}
+
+impl Sprite for Decoration {
+    fn sprite(&self, _viewer: Vec2) -> Image {self.frames[(self.timer * self.fps) as usize % self.frames.len()].clone()}
+    fn position(&self) -> Vec2 {self.position}
+    fn scale(&self) -> f32 {self.scale}
+    fn vertical_offset(&self) -> f32 {self.vertical_offset}
+}
+
+impl Decoration {
+    pub fn new<T: Into<Vec2>>(ctx: &Context, sprite_path: &str, position: T, scale: f32) -> Result<Decoration, GameError>{
+        Decoration::new_animated(ctx, &[sprite_path], position, scale, 0.0)
+    }
+
+    /// Creates a decoration cycling through the sprites at `sprite_paths` at `fps` frames per second
+    fn new_animated<T: Into<Vec2>>(ctx: &Context, sprite_paths: &[&str], position: T, scale: f32, fps: f32) -> Result<Decoration, GameError>{
+        let frames = sprite_paths
+            .iter()
+            .map(|path| load_sprite(ctx, path))
+            .collect::<Result<Vec<Image>, GameError>>()?;
+        if frames.is_empty() {
+            return Err(GameError::ResourceLoadError(String::from("A decoration needs at least one sprite")));
+        }
+        Ok(
+            Decoration {
+                frames,
+                fps,
+                timer: 0.0,
+                position: position.into(),
+                scale,
+                vertical_offset: 0.0,
+                sound: None,
+            }
+        )
+    }
+
+    /// Floats the decoration above (positive) or below (negative) eye level, e.g. for hanging lamps
+    pub fn with_vertical_offset(self, vertical_offset: f32) -> Decoration {
+        Decoration {
+            vertical_offset,
+            ..self
+        }
+    }
+
+    /// Gives the decoration a looping sound, which stays silent if it fails to load
+    pub fn with_sound(self, ctx: &Context, sound_path: &str) -> Decoration {
+        Decoration {
+            sound: AmbientSound::load(ctx, sound_path),
+            ..self
+        }
+    }
+
+    pub fn advance(&mut self, delta: f32) {
+        self.timer += delta;
+    }
+
+    /// Fades and pans the decoration's sound, if it has one, to match where it is relative to `player`
+    pub fn update_sound(&mut self, player: &Player) {
+        if let Some(sound) = &mut self.sound {
+            let offset = self.position - player.position;
+            let relative_position = vec2(offset.dot(player.camera.normalize()), offset.dot(player.direction.normalize()));
+            sound.update(relative_position);
+        }
+    }
+}
+
+#[derive(Clone, Copy)]
+pub enum ItemKind {
+    Coin,
+    Key(u32), // Identified by id, so each key fits its own doors
+}
+
+/// Something the player picks up by walking over it
+pub struct Item {
+    pub kind: ItemKind,
+    pub decoration: Decoration, // How the item looks lying in the level
+}
+
+#[derive(PartialEq, Eq, Clone, Copy)]
+enum EnemyState {
+    Idle, // Hasn't seen the player yet
+    Alert, // Has seen the player
+    Hurt, // Flinching after being shot, which also alerts it once it recovers
+}
+
+pub struct Enemy {
+    pub position: Vec2,
+    facing: Vec2,
+    state: EnemyState,
+    idle_frames: Vec<Vec<Image>>, // Indexed by direction, then animation frame
+    alert_frames: Vec<Vec<Image>>, // Indexed by direction, then animation frame
+    hurt_frames: Vec<Vec<Image>>, // Indexed by direction, then animation frame
+    timer: f32, // In seconds
+    pub health: u32, // Shots left before the enemy dies
+    hurt_timer: f32, // Seconds left of flinching while hurt
+    path: Option<Vec<(i32, i32)>>, // The last path found to the target's tile, None if it couldn't be reached
+    path_ends: ((i32, i32), (i32, i32)), // The enemy's and the target's tiles when `path` was found
+    repath_timer: f32, // Seconds until the path is found again anyway, so doors opening and closing are noticed
+}
+
+impl Sprite for Enemy {
+    fn sprite(&self, viewer: Vec2) -> Image {
+        let frames = match self.state {
+            EnemyState::Idle => &self.idle_frames,
+            EnemyState::Alert => &self.alert_frames,
+            EnemyState::Hurt => &self.hurt_frames,
+        };
+        // Measured from the enemy's facing, so a viewer straight ahead sees the front.
+        // Rounding to the nearest direction and wrapping with rem_euclid handles angles on either side of 0.
+        let to_viewer = viewer - self.position;
+        let angle = self.facing.angle_between(to_viewer);
+        let direction = ((angle / TAU * ENEMY_DIRECTIONS as f32).round() as i32).rem_euclid(ENEMY_DIRECTIONS as i32) as usize;
+        let frames = &frames[direction];
+        frames[(self.timer * ENEMY_FPS) as usize % frames.len()].clone()
+    }
+    fn position(&self) -> Vec2 {self.position}
+    fn scale(&self) -> f32 {ENEMY_SCALE}
+}
+
+impl Enemy {
+    pub fn new(ctx: &Context, position: Vec2) -> Result<Enemy, GameError> {
+        let load_frames = |state: &str| {
+            (0..ENEMY_DIRECTIONS)
+                .map(|direction| {
+                    (0..ENEMY_FRAME_COUNT)
+                        .map(|frame| load_sprite(ctx, &format!("{ENEMY_SPRITE_DIRECTORY}/{state}_{direction}_{frame}.png")))
+                        .collect::<Result<Vec<Image>, GameError>>()
+                })
+                .collect::<Result<Vec<Vec<Image>>, GameError>>()
+        };
+        Ok(Enemy {
+            position,
+            facing: vec2(0.0, 1.0),
+            state: EnemyState::Idle,
+            idle_frames: load_frames("idle")?,
+            alert_frames: load_frames("alert")?,
+            hurt_frames: load_frames("hurt")?,
+            timer: 0.0,
+            health: ENEMY_HEALTH,
+            hurt_timer: 0.0,
+            path: None,
+            path_ends: ((0, 0), (0, 0)),
+            repath_timer: 0.0,
+        })
+    }
+
+    pub fn advance(&mut self, delta: f32) {
+        self.timer += delta;
+        if self.state == EnemyState::Hurt {
+            self.hurt_timer -= delta;
+            if self.hurt_timer <= 0.0 {
+                self.state = EnemyState::Alert;
+            }
+        }
+    }
+
+    /// Takes `damage` points of damage and flinches
+    pub fn take_hit(&mut self, damage: u32) {
+        self.health = self.health.saturating_sub(damage);
+        self.state = EnemyState::Hurt;
+        self.hurt_timer = ENEMY_HURT_DURATION;
+    }
+
+    /// Becomes alert once the player is close enough and not hidden behind a wall
+    pub fn look_for(&mut self, target: Vec2, level: &Level) {
+        if self.state == EnemyState::Idle
+        && self.position.distance(target) <= ENEMY_SIGHT_DISTANCE
+        && has_line_of_sight(level, self.position, target) {
+            self.state = EnemyState::Alert;
+        }
+    }
+
+    /// Follows the shortest path towards the target's tile, one tile center at a time.
+    /// Only alert enemies give chase, and they stop once they've reached the target's tile.
+    /// The path is only searched for again when the enemy or the target moves to another tile,
+    /// or every `ENEMY_REPATH_INTERVAL` seconds.
+    pub fn chase(&mut self, target: Vec2, level: &Level, delta: f32) {
+        if self.state != EnemyState::Alert {
+            return;
+        }
+        let tile = |position: Vec2| (position.x.floor() as i32, position.y.floor() as i32);
+        let ends = (tile(self.position), tile(target));
+        self.repath_timer -= delta;
+        if ends != self.path_ends || self.repath_timer <= 0.0 {
+            let walkable = |(x, y): (i32, i32)| !level.is_solid(x, y);
+            self.path = crate::pathfinding::astar(walkable, ends.0, ends.1);
+            self.path_ends = ends;
+            self.repath_timer = ENEMY_REPATH_INTERVAL;
+        }
+        let Some((next_x, next_y)) = self.path.as_ref().and_then(|path| path.get(1)).copied() else {
+            return;
+        };
+        let waypoint = vec2(next_x as f32 + 0.5, next_y as f32 + 0.5);
+        let step = (waypoint - self.position).clamp_length_max(ENEMY_SPEED * delta);
+        if step != Vec2::ZERO {
+            self.facing = step.normalize();
+        }
+        let moved = self.position + step;
+        if !level.collides(moved, COLLISION_RADIUS) {
+            self.position = moved;
+        }
+    }
+}
+
+/// A tiny colored speck flying through the level, for effects like blood, sparks and dust.
+/// Drawn as a billboard like any other sprite.
+pub struct Particle {
+    pub image: Image, // A single white pixel, shared by every particle and tinted with `color`
+    pub position: Vec2,
+    pub height: f32, // Above the floor, as a fraction of wall height
+    pub velocity: Vec2, // In units / second
+    pub vertical_velocity: f32, // In wall heights / second
+    pub age: f32, // In seconds
+    pub color: Color,
+}
+
+impl Sprite for Particle {
+    fn sprite(&self, _viewer: Vec2) -> Image {self.image.clone()}
+    fn position(&self) -> Vec2 {self.position}
+    fn scale(&self) -> f32 {PARTICLE_SIZE * (1.0 - self.age / PARTICLE_LIFETIME)}
+    fn vertical_offset(&self) -> f32 {self.height - CAMERA_HEIGHT}
+    fn tint(&self) -> Color {self.color}
+}
+
+impl Particle {
+    pub fn is_expired(&self) -> bool {
+        self.age >= PARTICLE_LIFETIME
+    }
+
+    /// Moves the particle along, falling until it lands on the floor
+    pub fn advance(&mut self, delta: f32) {
+        self.age += delta;
+        self.vertical_velocity -= PARTICLE_GRAVITY * delta;
+        self.height += self.vertical_velocity * delta;
+        match self.height <= 0.0 {
+            true => {
+                self.height = 0.0;
+                self.vertical_velocity = 0.0;
+            }
+            false => self.position += self.velocity * delta,
+        }
+    }
+}
+
+/// A weapon the player carries, drawn in front of the view while it's held
+pub struct Weapon {
+    frames: Vec<Image>, // The idle frame followed by the firing animation
+    fire_timer: Option<f32>, // Seconds since the weapon was fired, while the animation is playing
+    fire_rate: f32, // In shots / second
+    pub damage: u32, // Health taken from an enemy per hit
+    cooldown: f32, // Seconds left until the weapon can fire again
+}
+
+impl Weapon {
+    /// Loads the weapon's frames from `sprite_directory`.
+    /// If any of them fail to load, that's reported and the weapon is drawn with the missing texture instead,
+    /// so a missing viewmodel never stops the game.
+    pub fn new(ctx: &Context, sprite_directory: &str, fire_rate: f32, damage: u32, texture_size: u32) -> Weapon {
+        let frames = (0..WEAPON_FRAME_COUNT)
+            .map(|frame| load_sprite(ctx, &format!("{sprite_directory}/{frame}.png")))
+            .collect::<Result<Vec<Image>, GameError>>()
+            .unwrap_or_else(|error| {
+                eprintln!("Warning: failed loading the weapon sprites in {sprite_directory}, using the missing texture: {error}");
+                vec![missing_texture(ctx, texture_size)]
+            });
+        Weapon { frames, fire_timer: None, fire_rate, damage, cooldown: 0.0 }
+    }
+
+    /// Fires if the weapon has cooled down since the last shot, restarting the firing animation.
+    /// Returns whether it fired.
+    pub fn fire(&mut self) -> bool {
+        if self.cooldown > 0.0 {
+            return false;
+        }
+        self.cooldown = 1.0 / self.fire_rate;
+        self.fire_timer = Some(0.0);
+        true
+    }
+
+    /// Advances the cooldown and the firing animation, going back to idle once it has played through once
+    pub fn advance(&mut self, delta: f32) {
+        self.cooldown = (self.cooldown - delta).max(0.0);
+        if let Some(timer) = &mut self.fire_timer {
+            *timer += delta;
+            if (*timer * WEAPON_FPS) as usize >= self.frames.len() {
+                self.fire_timer = None;
+            }
+        }
+    }
+
+    fn current_frame(&self) -> &Image {
+        let frame = match self.fire_timer {
+            Some(timer) => (timer * WEAPON_FPS) as usize,
+            None => 0,
+        };
+        &self.frames[frame.min(self.frames.len() - 1)]
+    }
+
+    /// Draws the weapon at the bottom center of the screen, swaying in step with the view bob.
+    /// `lowered` moves it down out of view, from 0 being fully raised to 1 being fully lowered.
+    pub fn draw(&self, canvas: &mut Canvas, player: &Player, config: &Config, lowered: f32) {
+        let image = self.current_frame();
+        let scale = config.y_resolution * WEAPON_HEIGHT / image.height() as f32;
+        let size = vec2(image.width() as f32, image.height() as f32) * scale;
+        // Swings to one side and dips with every step, coming to rest centered when the bob settles
+        let [sway_x, sway_y] = WEAPON_SWAY;
+        let phase = player.bob_phase;
+        let sway = vec2(sway_x * phase.sin(), sway_y * phase.sin().powi(2)) * config.y_resolution;
+        let position = vec2((config.x_resolution - size.x) / 2.0, config.y_resolution - size.y * (1.0 - lowered)) + sway;
+        let weapon_params = DrawParam::new()
+        .dest(position.floor())
+        .scale(vec2(scale, scale))
+        .z(1);
+        canvas.draw(image, weapon_params);
+    }
+}
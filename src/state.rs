@@ -0,0 +1,1597 @@
+use std::collections::VecDeque;
+use std::f32::consts::TAU;
+use std::time::{Duration, Instant};
+use crate::audio::Audio;
+use crate::config::Config;
+use crate::demo::{DemoPlayer, DemoRecorder, FrameInput};
+use crate::event_loop::RawMouseHandler;
+use crate::level::{Event, Level, RayHit, Tile, LIFT_STEP, cast_ray, cast_ray_through, layer_at};
+use crate::parsing::load_level;
+use crate::player::{Player, CAMERA_HEIGHT, COLLISION_RADIUS, PLAYER_MAX_HEALTH};
+use crate::render::{CeilingMode, FlatBatch, FloorMode, Gfx, Sky, TextureManifest, WallTexture, cast_flats, cast_walls, missing_texture, place_frame, sort_far_to_near, sprite_depth};
+use crate::sprites::{Enemy, ItemKind, Particle, Sprite, Weapon};
+use rayon::prelude::*;
+use serde::{Deserialize, Serialize};
+use ggez::{
+    event,
+    graphics::{self, BlendMode, Color, Image, ImageFormat, DrawParam, Rect, Text, Quad, InstanceArray, Canvas},
+    input::{keyboard::{KeyCode, KeyInput}, mouse::{self, MouseButton}, gamepad::gilrs::Axis},
+    glam::{vec2, Vec2},
+    timer::TimeContext,
+    Context,
+    GameError,
+};
+
+// Gameplay parameters
+const MAX_CAMERA_HEIGHT: f32 = 0.95; // Lifts can't raise the camera to the ceiling, or it would be seen from above
+const USE_DISTANCE: f32 = 1.0; // How far in front of the player things can be used, in units
+const ENEMY_HIT_RADIUS: f32 = 0.3; // How close to an enemy's center a shot has to pass to hit it, in units
+const MUZZLE_FLASH_DURATION: f32 = 0.06; // In seconds
+const PARTICLE_SPEED: f32 = 1.5; // Fastest a particle flies off from where it was emitted, in units / second
+const MAX_PARTICLES: usize = 256; // Every particle is drawn as its own sprite, so bursts past this many are cut short
+const ENEMY_TOUCH_DAMAGE: f32 = 20.0; // Health lost when an enemy touches the player
+const ENEMY_TOUCH_DISTANCE: f32 = 0.5; // How close an enemy has to get to touch the player, in units
+const DAMAGE_FLASH_DURATION: f32 = 0.3; // In seconds
+const BUMP_INTERVAL: f32 = 0.5; // Shortest time between bumps when walking into walls, so sliding along one doesn't keep bumping, in seconds
+const MESSAGE_DURATION: f32 = 1.5; // How long on-screen messages stay up by default, in seconds
+const MAX_MESSAGES: usize = 4; // How many messages can be up at once, the oldest making way for new ones
+pub const MAX_DELTA: f32 = 1.0 / 30.0; // Longest frame time simulated at once, in seconds. Keeps hitches from moving the player through walls
+const SPRINT_MULTIPLIER: f32 = 1.8; // Movement speed while sprinting, relative to walking
+const TICK_LENGTH: f32 = 1.0 / 60.0; // Movement is simulated in fixed steps of this length, in seconds
+
+// Rendering parameters
+const ZOOM_FACTOR: f32 = 0.4; // Field of view while zoomed, relative to the regular one
+const WEAPON_SWITCH_DURATION: f32 = 0.2; // How long lowering or raising a weapon takes when switching, in seconds
+const SHAKE_DISTANCE: f32 = 6.0; // How far the view jitters at full shake, in pixels
+const SHAKE_DECAY: f32 = 2.0; // How quickly shake dies down, in full shakes / second
+const DAMAGE_SHAKE: f32 = 0.5; // How hard the view shakes when the player gets hurt, 1 being full shake
+const BUMP_SHAKE: f32 = 0.1; // How hard the view shakes when the player walks into a wall
+const BRIGHTNESS_STEP: f32 = 0.1; // How much the brightness keys change the brightness by
+const BRIGHTNESS_RANGE: (f32, f32) = (0.5, 2.0);
+const FOV_STEP: f32 = 5.0; // How much the field of view keys widen or narrow the view, in degrees
+const FOV_RANGE: (f32, f32) = (30.0, 120.0); // Horizontal, in degrees
+const FADE_DURATION: f32 = 0.4; // How long fading to or from black takes, in seconds
+const CROSSHAIR_SIZE: f32 = 7.0; // Length of each crosshair line, in pixels. Odd so the lines cross on a single pixel
+
+// Misc parameters
+pub const CONFIG_PATH: &str = "config.toml";
+const SCREENSHOT_DIRECTORY: &str = "screenshots";
+const QUICKSAVE_PATH: &str = "quicksave.json";
+const GOLDEN_TOLERANCE: f32 = 0.01; // Largest mean difference per color channel, from 0 to 1, for a frame to match a golden image
+const SHAKE_SEED: u64 = 0x9E3779B97F4A7C15; // Fixed so shaking plays out the same way every run
+const PARTICLE_SEED: u64 = 0xD1B54A32D192ED03; // Fixed so particles fly the same way every run
+
+/// Where the game is in fading between levels
+#[derive(PartialEq, Eq, Clone, Copy)]
+enum Transition {
+    FadingIn, // Coming out of black after a level was loaded
+    Playing,
+    FadingOut, // Going to black, after which the level gets swapped
+}
+
+/// The player's state as written to a save file
+#[derive(Serialize, Deserialize)]
+struct SaveData {
+    map_path: String, // Saves only load into the level they were made in
+    position: [f32; 2],
+    direction: [f32; 2],
+    health: f32,
+    coins: u32,
+    keys: Vec<u32>,
+    #[serde(default)] // Saves from before the minimap was explored start out unexplored
+    explored: Vec<Vec<bool>>,
+}
+
+/// An entry in the pause menu
+#[derive(Clone, Copy)]
+enum PauseOption {
+    Resume,
+    Quit,
+}
+
+impl PauseOption {
+    fn label(self) -> &'static str {
+        match self {
+            PauseOption::Resume => "Resume",
+            PauseOption::Quit => "Quit",
+        }
+    }
+}
+
+const PAUSE_MENU: [PauseOption; 2] = [PauseOption::Resume, PauseOption::Quit];
+
+pub struct GameState {
+    pub config: Config,
+    level: Level,
+    player: Player,
+    gfx: Gfx,
+    audio: Audio,
+    weapons: Vec<Weapon>, // In the order of the config's `weapons`
+    current_weapon: usize, // Index into `weapons` of the one being held
+    switching_to: Option<usize>, // The weapon to raise once the current one is lowered
+    weapon_lowered: f32, // How far the held weapon is lowered while switching, from 0 to 1 being out of view
+    time_context: TimeContext,
+    frame_end: Instant, // When the last frame was due to end, which the frame rate cap counts the next one from
+    input: FrameInput, // What the player did this update, read live or from a demo
+    pub recorder: Option<DemoRecorder>, // Set when recording a demo, saved when the game quits
+    pub replay: Option<DemoPlayer>, // Set when replaying a demo, which is used instead of live input
+    has_focus: bool,
+    tick_accumulator: f32, // Time not yet simulated, in seconds
+    previous_position: Vec2, // Where the player was before the last tick
+    raw_mouse_delta: Vec2, // How far the mouse itself has moved since the last update, in device units
+    mouse_history: VecDeque<Vec2>, // The mouse movement of the last few updates, newest last, averaged when smoothing is on
+    show_minimap: bool,
+    nearest_sampling: bool, // Sample textures without filtering, keeping pixel art sharp
+    show_hud: bool, // The FPS counter and debug overlay, hidden when rendering headless so frames are reproducible
+    show_debug: bool,
+    messages: VecDeque<(String, f32)>, // Shown stacked near the bottom of the screen, oldest first, along with how many seconds each stays up
+    paused: bool,
+    pause_selection: usize, // Index into `PAUSE_MENU`
+    screenshot_requested: bool, // Set when the screenshot key is pressed, saved once the next frame is rendered
+    noclip: bool, // Lets the player move through walls, for getting around while testing levels
+    settings_dirty: bool, // Set when a setting that's saved to the config file changes, saved on pause or quit
+    flashlight: bool, // Lights up the center of the view and dims the rest
+    muzzle_flash: f32, // Seconds left of the flash after firing
+    damage_flash: f32, // Seconds left of the flash after taking damage
+    bump_cooldown: f32, // Seconds left until walking into a wall bumps again
+    shake: f32, // How hard the view is shaking, from 0 to 1
+    transition: Transition,
+    fade: f32, // How dark the screen is faded, from 0 to 1 being fully black
+    shake_rng: Rng,
+    particles: Vec<Particle>,
+    particle_image: Image, // The single white pixel every particle is drawn with
+    particle_rng: Rng,
+    elapsed: f32, // In seconds, drives texture animations
+    explored: Vec<Vec<bool>>, // Which walls have been seen, laid out like the map. Only these show up on the minimap
+    z_buffer: Vec<f32>, // How far away the nearest wall is for every screenspace x
+    wall_spans: Vec<(f32, f32)>, // Where the farthest wall starts and ends for every screenspace x
+    ray_directions: Vec<Vec2>, // Direction of the ray for every screenspace x, rebuilt when the camera turns
+    frame: Image, // The world is rendered here at the internal resolution, then scaled up to the window
+    frame_placement: Option<(f32, Vec2)>, // Scale and offset the frame is presented at, None while the window has no area
+}
+
+impl GameState {
+    pub fn new(ctx: &mut Context, config: Config, level: Level, player_position: Vec2, direction_vector: Vec2) -> Result<GameState, GameError> {
+        let manifest = TextureManifest::load(ctx)?;
+        let wall_textures = manifest.load_walls(ctx)?;
+        level.report_missing_textures(wall_textures.len());
+        let floor_image = match &level.floor_mode {
+            FloorMode::Tiled | FloorMode::Gradient => Image::from_path(ctx, "/textures/floor.png")?,
+            FloorMode::Parallax(image) => image.clone(),
+        };
+        let ceiling_image = match &level.ceiling_mode {
+            CeilingMode::Tiled | CeilingMode::Sky(_) | CeilingMode::Gradient => Image::from_path(ctx, "/textures/ceiling.png")?,
+            CeilingMode::Parallax(image) => image.clone(),
+        };
+        let mut floor_batches = vec![InstanceArray::new(ctx, floor_image)];
+        let mut ceiling_batches = vec![InstanceArray::new(ctx, ceiling_image)];
+        for path in &manifest.flats {
+            let image = Image::from_path(ctx, path)?;
+            floor_batches.push(InstanceArray::new(ctx, image.clone()));
+            ceiling_batches.push(InstanceArray::new(ctx, image));
+        }
+        let missing_texture = WallTexture::Static(missing_texture(ctx, config.texture_size));
+        let wall_batches = wall_textures
+            .iter()
+            .chain([&missing_texture])
+            .map(|texture| texture.frames().iter().map(|frame| InstanceArray::new(ctx, frame.clone())).collect())
+            .collect();
+        let gfx = Gfx {
+            wall_textures,
+            missing_texture,
+            wall_batches,
+            floor_batches,
+            ceiling_batches,
+            liquid_batch: InstanceArray::new(ctx, Image::from_pixels(ctx, &[255; 4], ImageFormat::Rgba8UnormSrgb, 1, 1)),
+        };
+        
+
+        let mut audio = Audio::load(ctx, config.music_volume);
+        audio.start_music(ctx);
+        let player = Player::new(player_position, direction_vector, config.camera_plane_length());
+        if config.weapons.is_empty() {
+            return Err(GameError::ConfigError(String::from("The config lists no weapons, the player needs at least one")));
+        }
+        let weapons = config.weapons
+            .iter()
+            .map(|weapon| Weapon::new(ctx, &weapon.sprites, weapon.fire_rate, weapon.damage, config.texture_size))
+            .collect::<Vec<Weapon>>();
+
+        let z_buffer = vec![0.0; config.x_resolution as usize];
+        let wall_spans = vec![(0.0, 0.0); config.x_resolution as usize];
+        let frame = Image::new_canvas_image(
+            ctx,
+            ctx.gfx.surface_format(),
+            config.x_resolution as u32,
+            config.y_resolution as u32,
+            1
+        );
+        let ray_directions = vec![Vec2::ZERO; config.x_resolution as usize];
+        let explored = unexplored(&level.map);
+        let (window_width, window_height) = ctx.gfx.drawable_size();
+        let frame_placement = place_frame(vec2(window_width, window_height), vec2(config.x_resolution, config.y_resolution));
+
+        Ok(GameState {
+            nearest_sampling: config.nearest_sampling,
+            config,
+            level,
+            player,
+            gfx,
+            audio,
+            weapons,
+            current_weapon: 0,
+            switching_to: None,
+            weapon_lowered: 0.0,
+            time_context: TimeContext::new(),
+            frame_end: Instant::now(),
+            input: FrameInput::default(),
+            recorder: None,
+            replay: None,
+            has_focus: true,
+            tick_accumulator: 0.0,
+            previous_position: player_position,
+            raw_mouse_delta: Vec2::ZERO,
+            mouse_history: VecDeque::new(),
+            show_minimap: false,
+            show_hud: true,
+            show_debug: false,
+            messages: VecDeque::new(),
+            paused: false,
+            pause_selection: 0,
+            screenshot_requested: false,
+            noclip: false,
+            settings_dirty: false,
+            flashlight: false,
+            muzzle_flash: 0.0,
+            damage_flash: 0.0,
+            bump_cooldown: 0.0,
+            shake: 0.0,
+            transition: Transition::FadingIn,
+            fade: 1.0,
+            shake_rng: Rng::new(SHAKE_SEED),
+            particles: Vec::new(),
+            particle_image: Image::from_pixels(ctx, &[255; 4], ImageFormat::Rgba8UnormSrgb, 1, 1),
+            particle_rng: Rng::new(PARTICLE_SEED),
+            elapsed: 0.0,
+            explored,
+            z_buffer,
+            wall_spans,
+            frame,
+            frame_placement,
+            ray_directions,
+        })
+    }
+
+    /// Advances everything that affects gameplay by one fixed-length tick
+    fn tick(&mut self, ctx: &Context) {
+        self.previous_position = self.player.position;
+        for door in &mut self.level.doors {
+            door.advance(TICK_LENGTH);
+        }
+        self.level.advance_secret_walls(TICK_LENGTH);
+        self.handle_movement(ctx, TICK_LENGTH);
+        self.use_teleporters();
+        self.ride_lifts();
+        self.fire_triggers(ctx);
+        self.collect_items();
+        // Enemies are taken out of the level while they move, so they can check it for walls
+        let mut enemies = std::mem::take(&mut self.level.enemies);
+        for enemy in &mut enemies {
+            enemy.look_for(self.player.position, &self.level);
+            enemy.chase(self.player.position, &self.level, TICK_LENGTH);
+        }
+        self.level.enemies = enemies;
+        self.take_enemy_damage();
+    }
+
+    /// Hurts the player if an enemy is touching them
+    fn take_enemy_damage(&mut self) {
+        self.player.damage_cooldown = (self.player.damage_cooldown - TICK_LENGTH).max(0.0);
+        let position = self.player.position;
+        let touching = self.level.enemies.iter().any(|enemy| enemy.position.distance(position) <= ENEMY_TOUCH_DISTANCE);
+        if touching && self.player.take_damage(ENEMY_TOUCH_DAMAGE) {
+            self.damage_flash = DAMAGE_FLASH_DURATION;
+            self.add_shake(DAMAGE_SHAKE);
+        }
+    }
+
+    /// Sends the player through the teleporter they're standing on, see [`Player::use_teleporters`]
+    fn use_teleporters(&mut self) {
+        if self.player.use_teleporters(&self.level) {
+            // Skip interpolating this tick, or the player would be drawn sweeping across the map
+            self.previous_position = self.player.position;
+        }
+    }
+
+    /// Raises or lowers the player towards the height of the lift they're standing on, or back to the floor
+    fn ride_lifts(&mut self) {
+        let position = self.player.position;
+        let steps = layer_at(&self.level.lift_map, position.x.floor() as i32, position.y.floor() as i32);
+        let target_height = (CAMERA_HEIGHT + steps as f32 * LIFT_STEP).min(MAX_CAMERA_HEIGHT);
+        self.player.rise_towards(target_height, TICK_LENGTH);
+    }
+
+    /// Fires the triggers on the player's tile that haven't fired yet
+    fn fire_triggers(&mut self, ctx: &Context) {
+        let position = self.player.position;
+        for event in self.level.fire_triggers(position.x.floor() as i32, position.y.floor() as i32) {
+            self.dispatch(ctx, event);
+        }
+    }
+
+    /// Makes an event happen
+    fn dispatch(&mut self, ctx: &Context, event: Event) {
+        match event {
+            Event::Message(text) => self.show_message(text, MESSAGE_DURATION),
+            Event::OpenDoor { x, y } => match self.level.doors.iter_mut().find(|door| door.x == x && door.y == y) {
+                Some(door) => {
+                    door.key_id = None;
+                    door.opening = true;
+                }
+                None => eprintln!("Warning: a trigger tried opening a door at ({x}, {y}), but there's none there"),
+            },
+            Event::SpawnEnemy { x, y } => match Enemy::new(ctx, vec2(x as f32 + 0.5, y as f32 + 0.5)) {
+                Ok(enemy) => self.level.enemies.push(enemy),
+                Err(error) => eprintln!("Warning: a trigger failed spawning an enemy: {error}"),
+            },
+        }
+    }
+
+    /// Picks up every item within reach of the player
+    fn collect_items(&mut self) {
+        let reach = self.config.pickup_radius;
+        let position = self.player.position;
+        let inventory = &mut self.player.inventory;
+        let mut picked_up = Vec::new();
+        self.level.items.retain(|item| {
+            if item.decoration.position.distance(position) > reach {
+                return true;
+            }
+            inventory.add(item.kind);
+            picked_up.push(item.kind);
+            false
+        });
+        for kind in picked_up {
+            let text = match kind {
+                ItemKind::Coin => String::from("Picked up a coin"),
+                ItemKind::Key(id) => format!("Picked up key {id}"),
+            };
+            self.show_message(text, MESSAGE_DURATION);
+        }
+    }
+
+    /// Handles the keys that are acted on for as long as they're held
+    fn handle_movement(&mut self, ctx: &Context, delta: f32) {
+        let keys = &self.config.keys;
+        let input = &self.input;
+        let direction = self.player.direction;
+        // Strafing moves along the camera plane instead of the view direction
+        let strafe = self.player.camera.normalize();
+        let mut wish_direction = Vec2::ZERO;
+        if input.is_held(keys.forward) {
+            wish_direction += direction;
+        }
+        if input.is_held(keys.back) {
+            wish_direction -= direction;
+        }
+        if input.is_held(keys.strafe_left) {
+            wish_direction -= strafe;
+        }
+        if input.is_held(keys.strafe_right) {
+            wish_direction += strafe;
+        }
+        let move_stick = Vec2::from(input.move_stick);
+        let turn_stick = input.turn_stick;
+        wish_direction += direction * move_stick.y + strafe * move_stick.x;
+        let speed = match input.is_held(keys.sprint) {
+            true => self.config.move_speed * SPRINT_MULTIPLIER,
+            false => self.config.move_speed,
+        };
+        // Wading through liquid slows the player down
+        let position = self.player.position;
+        let speed = match self.level.liquid_at(position.x.floor() as i32, position.y.floor() as i32) {
+            Some(liquid) => speed * self.config.liquids.get(&liquid).map_or(1.0, |liquid| liquid.speed),
+            None => speed,
+        };
+        // Clamping rather than normalizing lets a half-pushed stick walk at half speed
+        self.player.accelerate_towards(wish_direction.clamp_length_max(1.0) * speed, delta);
+        self.bump_cooldown = (self.bump_cooldown - delta).max(0.0);
+        let blocked = match self.noclip {
+            true => {
+                self.player.position += self.player.velocity * delta;
+                false
+            }
+            false => self.player.try_move(self.player.velocity * delta, &self.level),
+        };
+        // Faster steps bob faster, and footsteps land with the bob
+        if self.player.advance_bob(delta * speed / self.config.move_speed, wish_direction != Vec2::ZERO) {
+            let position = self.player.position;
+            let floor_texture = layer_at(&self.level.floor_map, position.x as i32, position.y as i32);
+            self.audio.play_footstep(ctx, floor_texture);
+        }
+        if self.input.is_held(keys.turn_left) {
+            self.player.rotate(self.config.rotation_speed * delta);
+        }
+        if self.input.is_held(keys.turn_right) {
+            self.player.rotate(-self.config.rotation_speed * delta);
+        }
+        if turn_stick != 0.0 {
+            self.player.rotate(-turn_stick * self.config.rotation_speed * delta);
+        }
+        // Zooming narrows the camera plane, which the walls, floor and ceiling are all cast from
+        let target_fov = match self.input.is_held(keys.zoom) {
+            true => self.config.camera_plane_length() * ZOOM_FACTOR,
+            false => self.config.camera_plane_length(),
+        };
+        self.player.zoom_towards(target_fov, delta);
+        if blocked && self.bump_cooldown == 0.0 {
+            self.bump_cooldown = BUMP_INTERVAL;
+            self.audio.play_bump(ctx);
+            self.add_shake(BUMP_SHAKE);
+        }
+    }
+
+    /// Takes a snapshot of the keyboard, mouse and gamepad for this update
+    fn read_input(&mut self, ctx: &Context) -> FrameInput {
+        let keyboard = &ctx.keyboard;
+        let held = keyboard.pressed_keys().iter().copied().collect::<Vec<KeyCode>>();
+        let pressed = held.iter().copied().filter(|key| keyboard.is_key_just_pressed(*key)).collect();
+        let (move_stick, turn_stick) = self.read_gamepad(ctx);
+        FrameInput {
+            delta: self.time_context.delta().as_secs_f32(),
+            held,
+            pressed,
+            clicked: ctx.mouse.button_just_pressed(MouseButton::Left),
+            mouse_delta: self.read_mouse_delta(),
+            move_stick: move_stick.into(),
+            turn_stick,
+        }
+    }
+
+    /// How far the mouse moved since the last update, ignored while the window is unfocused.
+    /// Read from the raw motion of the mouse rather than the cursor, which stops moving at the edge of the window.
+    fn read_mouse_delta(&mut self) -> [f32; 2] {
+        let mouse_delta = std::mem::take(&mut self.raw_mouse_delta);
+        match self.has_focus {
+            true => mouse_delta.to_array(),
+            false => [0.0, 0.0],
+        }
+    }
+
+    /// Reads the left stick of the first connected gamepad as movement, with y pointing forward,
+    /// and the x axis of its right stick as turning, with positive x turning right
+    fn read_gamepad(&self, ctx: &Context) -> (Vec2, f32) {
+        let Some((_, gamepad)) = ctx.gamepad.gamepads().next() else {
+            return (Vec2::ZERO, 0.0);
+        };
+        let deadzone = self.config.gamepad_deadzone;
+        let move_stick = vec2(gamepad.value(Axis::LeftStickX), gamepad.value(Axis::LeftStickY));
+        let turn_stick = vec2(gamepad.value(Axis::RightStickX), 0.0);
+        (apply_deadzone(move_stick, deadzone), apply_deadzone(turn_stick, deadzone).x)
+    }
+
+    /// Handles the keys that are acted on once per press
+    fn handle_input(&mut self) {
+        if self.input.clicked {
+            self.shoot();
+        }
+        let selected = self.config.keys.select_weapon
+            .iter()
+            .position(|key| self.input.was_pressed(Some(*key)));
+        if let Some(index) = selected {
+            self.switch_weapon(index);
+        }
+        if self.input.was_pressed(self.config.keys.use_door) {
+            self.use_door();
+        }
+        if self.input.was_pressed(self.config.keys.reload_level) {
+            self.start_level_change();
+        }
+        if self.input.was_pressed(self.config.keys.quicksave) {
+            let text = match self.save(QUICKSAVE_PATH) {
+                Ok(()) => String::from("Game saved"),
+                Err(error) => format!("Saving failed: {error}"),
+            };
+            self.show_message(text, MESSAGE_DURATION);
+        }
+        if self.input.was_pressed(self.config.keys.quickload) {
+            let text = match self.load(QUICKSAVE_PATH) {
+                Ok(()) => String::from("Game loaded"),
+                Err(error) => format!("Loading failed: {error}"),
+            };
+            self.show_message(text, MESSAGE_DURATION);
+        }
+        if self.input.was_pressed(self.config.keys.screenshot) {
+            self.screenshot_requested = true;
+        }
+        if self.input.was_pressed(self.config.keys.toggle_debug) {
+            self.show_debug = !self.show_debug;
+        }
+        if self.input.was_pressed(self.config.keys.toggle_noclip) {
+            self.noclip = !self.noclip;
+        }
+        if self.input.was_pressed(self.config.keys.toggle_flashlight) {
+            self.flashlight = !self.flashlight;
+        }
+        if self.input.was_pressed(self.config.keys.toggle_minimap) {
+            self.show_minimap = !self.show_minimap;
+        }
+        if self.input.was_pressed(self.config.keys.toggle_sampling) {
+            self.nearest_sampling = !self.nearest_sampling;
+        }
+        if self.input.was_pressed(self.config.keys.volume_up) {
+            self.change_music_volume(1.0);
+        }
+        if self.input.was_pressed(self.config.keys.volume_down) {
+            self.change_music_volume(-1.0);
+        }
+        if self.input.was_pressed(self.config.keys.brightness_up) {
+            self.change_brightness(1.0);
+        }
+        if self.input.was_pressed(self.config.keys.brightness_down) {
+            self.change_brightness(-1.0);
+        }
+        if self.input.was_pressed(self.config.keys.fov_up) {
+            self.change_fov(1.0);
+        }
+        if self.input.was_pressed(self.config.keys.fov_down) {
+            self.change_fov(-1.0);
+        }
+        if self.input.was_pressed(self.config.keys.toggle_mute) {
+            self.audio.toggle_mute();
+            let text = match self.audio.muted() {
+                true => String::from("Music muted"),
+                false => String::from("Music unmuted"),
+            };
+            self.show_message(text, MESSAGE_DURATION);
+        }
+    }
+
+    /// Fires a single shot straight ahead, hitting the nearest enemy in the line of fire
+    /// unless a wall is in the way. Enemies are hit if the shot passes within `ENEMY_HIT_RADIUS` of them.
+    fn shoot(&mut self) {
+        // Weapons can't fire while they're being lowered or raised
+        if self.weapon_lowered > 0.0 || !self.weapons[self.current_weapon].fire() {
+            return;
+        }
+        self.muzzle_flash = MUZZLE_FLASH_DURATION;
+        let damage = self.weapons[self.current_weapon].damage;
+        let origin = self.player.position;
+        let direction = self.player.direction.normalize();
+        let level = &self.level;
+        let wall_distance = cast_ray(&level.map, &level.doors, &level.secret_walls, origin, direction, self.config.render_distance)
+            .map_or(self.config.render_distance, |hit| hit.perpendicular_distance);
+        let target = self.level.enemies
+            .iter_mut()
+            .filter_map(|enemy| {
+                let offset = enemy.position - origin;
+                let distance_along = offset.dot(direction);
+                let distance_across = offset.perp_dot(direction).abs();
+                match distance_along > 0.0 && distance_along < wall_distance && distance_across <= ENEMY_HIT_RADIUS {
+                    true => Some((distance_along, enemy)),
+                    false => None,
+                }
+            })
+            .min_by(|(a, _), (b, _)| a.total_cmp(b));
+        // Blood where an enemy was hit, or dust where the shot hit the wall
+        let impact = match target {
+            Some((_, enemy)) => {
+                enemy.take_hit(damage);
+                (enemy.position, Color::new(0.6, 0.0, 0.0, 1.0))
+            }
+            None => (origin + direction * (wall_distance - COLLISION_RADIUS), Color::new(0.6, 0.55, 0.5, 1.0)),
+        };
+        self.level.enemies.retain(|enemy| enemy.health > 0);
+        let (position, color) = impact;
+        self.emit_burst(position, self.player.camera_height, 12, color);
+    }
+
+    /// Sends `count` particles of the given color flying off in every direction from a point
+    /// `height` above the floor, as far as `MAX_PARTICLES` allows
+    fn emit_burst(&mut self, position: Vec2, height: f32, count: usize, color: Color) {
+        let count = count.min(MAX_PARTICLES - self.particles.len());
+        for _ in 0..count {
+            let angle = self.particle_rng.next_f32() * TAU;
+            let speed = self.particle_rng.next_f32() * PARTICLE_SPEED;
+            let vertical_velocity = (self.particle_rng.next_f32() * 2.0 - 0.5) * PARTICLE_SPEED;
+            self.particles.push(Particle {
+                image: self.particle_image.clone(),
+                position,
+                height,
+                velocity: vec2(angle.cos(), angle.sin()) * speed,
+                vertical_velocity,
+                age: 0.0,
+                color,
+            });
+        }
+    }
+
+    /// Starts lowering the held weapon to switch to the one at `index`, if the player has one there
+    fn switch_weapon(&mut self, index: usize) {
+        if index >= self.weapons.len() {
+            return;
+        }
+        self.switching_to = match index == self.current_weapon {
+            true => None,
+            false => Some(index),
+        };
+    }
+
+    /// Lowers the held weapon while switching, swaps it out once it's out of view,
+    /// and raises the held weapon otherwise
+    fn advance_weapon_switch(&mut self, delta: f32) {
+        let step = delta / WEAPON_SWITCH_DURATION;
+        match self.switching_to {
+            Some(index) => {
+                self.weapon_lowered = (self.weapon_lowered + step).min(1.0);
+                if self.weapon_lowered >= 1.0 {
+                    self.current_weapon = index;
+                    self.switching_to = None;
+                }
+            }
+            None => self.weapon_lowered = (self.weapon_lowered - step).max(0.0),
+        }
+    }
+
+    /// Changes the music volume by `steps` volume steps, to be saved to the config file later
+    fn change_music_volume(&mut self, steps: f32) {
+        self.audio.change_music_volume(steps);
+        self.config.music_volume = self.audio.music_volume();
+        self.settings_dirty = true;
+        let percent = (self.config.music_volume * 100.0).round();
+        self.show_message(format!("Music volume: {percent}%"), MESSAGE_DURATION);
+    }
+
+    /// Widens or narrows the view by `steps` field of view steps.
+    /// The player eases into the new field of view the same way as when zooming.
+    fn change_fov(&mut self, steps: f32) {
+        let (min, max) = FOV_RANGE;
+        let degrees = (self.config.horizontal_fov() + steps * FOV_STEP).clamp(min, max);
+        self.config.set_horizontal_fov(degrees);
+        self.show_message(format!("FOV: {degrees:.0}°"), MESSAGE_DURATION);
+    }
+
+    /// Changes the brightness by `steps` brightness steps, to be saved to the config file later
+    fn change_brightness(&mut self, steps: f32) {
+        let (min, max) = BRIGHTNESS_RANGE;
+        self.config.brightness = (self.config.brightness + steps * BRIGHTNESS_STEP).clamp(min, max);
+        self.settings_dirty = true;
+        let percent = (self.config.brightness * 100.0).round();
+        self.show_message(format!("Brightness: {percent}%"), MESSAGE_DURATION);
+    }
+
+    /// Writes the settings changed in game to the config file, if any have changed since they were last saved.
+    /// Done on pause and quit rather than on every change, so holding a key down doesn't rewrite the file each step.
+    fn save_settings(&mut self) {
+        if !self.settings_dirty {
+            return;
+        }
+        match self.config.save_settings(CONFIG_PATH) {
+            Ok(()) => self.settings_dirty = false,
+            Err(error) => eprintln!("Warning: {error}"),
+        }
+    }
+
+    /// Writes the player's position, direction, health and inventory to `path`.
+    /// The state of the level itself, like opened doors or collected items, isn't saved.
+    fn save(&self, path: &str) -> Result<(), GameError> {
+        let player = &self.player;
+        let save_data = SaveData {
+            map_path: self.config.map_path.clone(),
+            position: player.position.to_array(),
+            direction: player.direction.to_array(),
+            health: player.health,
+            coins: player.inventory.coins,
+            keys: player.inventory.keys.clone(),
+            explored: self.explored.clone(),
+        };
+        let save_str = serde_json::to_string_pretty(&save_data)
+            .map_err(|error| GameError::CustomError(format!("Failed serializing the save: {error}")))?;
+        std::fs::write(path, save_str)
+            .map_err(|error| GameError::CustomError(format!("Failed writing {path}: {error}")))
+    }
+
+    /// Restores the player's state from a save written by [`GameState::save`]
+    fn load(&mut self, path: &str) -> Result<(), GameError> {
+        let save_str = std::fs::read_to_string(path)
+            .map_err(|error| GameError::CustomError(format!("Failed reading {path}: {error}")))?;
+        let save_data: SaveData = serde_json::from_str(&save_str)
+            .map_err(|error| GameError::CustomError(format!("Failed parsing {path}: {error}")))?;
+        if save_data.map_path != self.config.map_path {
+            return Err(GameError::CustomError(format!("{path} is a save for {}", save_data.map_path)));
+        }
+        let direction = Vec2::from_array(save_data.direction);
+        if !direction.is_finite() || direction == Vec2::ZERO {
+            return Err(GameError::CustomError(format!("{path} has an invalid direction")));
+        }
+        let player = &mut self.player;
+        player.position = Vec2::from_array(save_data.position);
+        player.velocity = Vec2::ZERO;
+        player.direction = direction.normalize();
+        // The camera plane has to stay perpendicular to the new direction, at the current field of view
+        player.set_fov(player.fov());
+        player.health = save_data.health;
+        player.inventory.coins = save_data.coins;
+        player.inventory.keys = save_data.keys;
+        self.previous_position = player.position;
+        // Only keep the explored map if it's laid out like this level's map
+        let same_shape = save_data.explored.iter().map(Vec::len).eq(self.explored.iter().map(Vec::len));
+        self.explored = match same_shape {
+            true => save_data.explored,
+            false => unexplored(&self.level.map),
+        };
+        Ok(())
+    }
+
+    /// Fades to black, after which the level is reloaded and faded back in.
+    /// Fading out picks up from however dark the screen already is.
+    fn start_level_change(&mut self) {
+        self.transition = Transition::FadingOut;
+    }
+
+    /// Advances the fade, swapping the level once the screen is fully black
+    fn advance_transition(&mut self, ctx: &Context, delta: f32) {
+        let step = delta / FADE_DURATION;
+        match self.transition {
+            Transition::FadingIn => {
+                self.fade = (self.fade - step).max(0.0);
+                if self.fade == 0.0 {
+                    self.transition = Transition::Playing;
+                }
+            }
+            Transition::Playing => (),
+            Transition::FadingOut => {
+                self.fade = (self.fade + step).min(1.0);
+                if self.fade == 1.0 {
+                    self.reload_level(ctx);
+                    self.transition = Transition::FadingIn;
+                }
+            }
+        }
+    }
+
+    /// Reloads the level from disk and puts the player back at its spawn.
+    /// If the map can't be loaded, the current level is kept.
+    fn reload_level(&mut self, ctx: &Context) {
+        match load_level(ctx, &self.config) {
+            Ok((level, spawn_position, spawn_direction)) => {
+                level.report_missing_textures(self.gfx.wall_textures.len());
+                let player = Player::new(spawn_position, spawn_direction, self.config.camera_plane_length());
+                self.set_level(level, player);
+                println!("Reloaded {}", self.config.map_path);
+            }
+            Err(error) => eprintln!("Failed reloading level, keeping the current one: {error}"),
+        }
+    }
+
+    /// Swaps in another level and player, forgetting everything tied to the old level
+    fn set_level(&mut self, level: Level, player: Player) {
+        self.previous_position = player.position;
+        self.explored = unexplored(&level.map);
+        self.level = level;
+        self.player = player;
+        self.particles.clear();
+    }
+
+    /// Opens or closes the door in front of the player, if there is one
+    fn use_door(&mut self) {
+        let target = self.player.position + self.player.direction * USE_DISTANCE;
+        let (x, y) = (target.x.floor() as i32, target.y.floor() as i32);
+        let position = self.player.position;
+        if let Some(door) = self.level.doors.iter_mut().find(|door| door.x == x && door.y == y) {
+            // Unlocking uses up the key, after which the door works like any other
+            if let Some(key_id) = door.key_id {
+                if !door.try_unlock(&mut self.player.inventory.keys) {
+                    self.show_message(format!("Locked, needs key {key_id}"), MESSAGE_DURATION);
+                    return;
+                }
+            }
+            // Don't close the door on the player
+            let in_doorway = position.x + COLLISION_RADIUS > x as f32 && position.x - COLLISION_RADIUS < (x + 1) as f32
+                && position.y + COLLISION_RADIUS > y as f32 && position.y - COLLISION_RADIUS < (y + 1) as f32;
+            if door.opening && in_doorway {
+                return;
+            }
+            door.opening = !door.opening;
+            return;
+        }
+        if self.level.push_secret_wall(x, y) {
+            self.show_message("You found a secret!", MESSAGE_DURATION);
+        }
+    }
+
+    fn handle_mouse(&mut self) {
+        let settings = &self.config.mouse;
+        let mut mouse_delta = Vec2::from(self.input.mouse_delta);
+        if settings.smoothing {
+            self.mouse_history.push_back(mouse_delta);
+            while self.mouse_history.len() > settings.smoothing_frames.max(1) {
+                self.mouse_history.pop_front();
+            }
+            mouse_delta = self.mouse_history.iter().sum::<Vec2>() / self.mouse_history.len() as f32;
+        }
+        if settings.invert_y {
+            mouse_delta.y = -mouse_delta.y;
+        }
+        // Only turn when the mouse actually moved, so the cached ray directions stay valid
+        if mouse_delta.x != 0.0 {
+            self.player.rotate(-mouse_delta.x * settings.sensitivity);
+        }
+        self.player.tilt(-mouse_delta.y * settings.sensitivity);
+    }
+
+    /// Sleeps out whatever is left of the frame budget when `max_fps` is set.
+    /// The time slept is part of the next frame's delta, so movement speed is the same at any cap.
+    fn limit_frame_rate(&mut self) {
+        let Some(max_fps) = self.config.max_fps.filter(|max_fps| *max_fps > 0.0) else {
+            return;
+        };
+        let now = Instant::now();
+        let frame_end = self.frame_end + Duration::from_secs_f32(1.0 / max_fps);
+        // A frame that ran over starts the budget over instead of rushing the next ones to catch up
+        self.frame_end = match frame_end > now {
+            true => {
+                std::thread::sleep(frame_end - now);
+                frame_end
+            }
+            false => now,
+        };
+    }
+
+    /// Recomputes the direction of the ray for every screenspace x after the camera has turned
+    fn update_ray_directions(&mut self) {
+        self.player.aim_rays(&mut self.ray_directions, self.config.x_resolution);
+        self.player.rays_dirty = false;
+    }
+
+    /// Draws the part of every sky layer the player is facing above the horizon, farthest first
+    fn draw_sky(&self, canvas: &mut Canvas, sky: &Sky, horizon: f32) {
+        for (image, scroll) in &sky.layers {
+            self.draw_sky_layer(canvas, image, *scroll, horizon);
+        }
+    }
+
+    /// Draws the part of a panoramic sky image the player is facing above the horizon,
+    /// scrolling `scroll` times the width of the image per full turn
+    fn draw_sky_layer(&self, canvas: &mut Canvas, sky: &Image, scroll: f32, horizon: f32) {
+        let x_resolution = self.config.x_resolution;
+        let left_ray = self.player.direction - self.player.camera;
+        // Angles decrease from left to right on screen, so negate them to scroll the right way
+        let start = (-left_ray.y.atan2(left_ray.x) / TAU * scroll).rem_euclid(1.0);
+        // Faster layers show less of their image at once, which makes them scroll faster across the screen
+        let width = (2.0 * (self.player.camera.length() / self.player.direction.length()).atan() / TAU * scroll).min(1.0);
+        let y_scale = horizon / sky.height() as f32;
+        // The visible part may wrap around the edge of the image, which takes two draws
+        let first_width = width.min(1.0 - start);
+        let slices = [(start, first_width, 0.0), (0.0, width - first_width, first_width)];
+        for (src_x, src_width, screen_offset) in slices {
+            if src_width <= 0.0 {
+                continue;
+            }
+            let params = DrawParam::new()
+            .src(Rect::new(src_x, 0.0, src_width, 1.0))
+            .dest(vec2(x_resolution * screen_offset / width, 0.0))
+            .scale(vec2(x_resolution / (width * sky.width() as f32), y_scale))
+            .color(self.config.brightness_tint())
+            .z(i32::MIN);
+            canvas.draw(sky, params);
+        }
+    }
+
+    /// Fills the rows between the horizon and `edge`, the top or bottom of the screen, with the config's gradient,
+    /// one flat quad per row
+    fn draw_gradient(&self, canvas: &mut Canvas, horizon: f32, edge: f32) {
+        let rows = (edge - horizon).abs().ceil() as u32;
+        for row in 0..rows {
+            let y = match edge < horizon {
+                true => horizon - row as f32 - 1.0,
+                false => horizon + row as f32,
+            };
+            let params = DrawParam::new()
+            .dest(vec2(0.0, y))
+            .scale(vec2(self.config.x_resolution, 1.0))
+            .color(self.config.gradient_color((row as f32 + 0.5) / rows as f32))
+            .z(i32::MIN);
+            canvas.draw(&Quad, params);
+        }
+    }
+
+    /// Renders the world to the offscreen frame and presents it
+    fn render(&mut self, ctx: &mut Context) -> Result<(), GameError> {
+        self.render_world(ctx)?;
+        if self.screenshot_requested {
+            self.screenshot_requested = false;
+            // A failed screenshot isn't worth stopping the game over
+            match self.save_screenshot(ctx) {
+                Ok(path) => println!("Saved screenshot to {path}"),
+                Err(error) => eprintln!("Failed saving screenshot: {error}"),
+            }
+        }
+        self.present_frame(ctx)
+    }
+
+    /// Renders a single frame of `level` as seen by `player`, without the HUD and without touching the window,
+    /// for regression tests and tools. Settings and textures come from this state, whose level and player are replaced.
+    /// The returned image is the state's frame, so the next render draws over it.
+    fn render_frame(&mut self, ctx: &mut Context, level: Level, player: Player) -> Result<Image, GameError> {
+        self.set_level(level, player);
+        self.show_hud = false;
+        // Skip the fade in, or the frame would come out black
+        self.transition = Transition::Playing;
+        self.fade = 0.0;
+        self.render_world(ctx)?;
+        Ok(self.frame.clone())
+    }
+
+    /// Renders the world to the offscreen frame, without touching the window
+    fn render_world(&mut self, ctx: &mut Context) -> Result<(), GameError> {
+        let mut canvas = graphics::Canvas::from_image(
+            ctx,
+            self.frame.clone(),
+            self.config.fog_color(),
+        );
+        let x_resolution = self.config.x_resolution;
+        let y_resolution = self.config.y_resolution;
+        // The world is jittered while shaking, the overlays drawn on top of it aren't
+        let shake_offset = match self.shake > 0.0 {
+            true => {
+                let jitter = vec2(self.shake_rng.next_f32(), self.shake_rng.next_f32()) * 2.0 - Vec2::ONE;
+                (jitter * self.shake * SHAKE_DISTANCE).round()
+            }
+            false => Vec2::ZERO,
+        };
+        canvas.set_screen_coordinates(Rect::new(shake_offset.x, shake_offset.y, x_resolution, y_resolution));
+        // Applies to everything drawn on this canvas, floor and ceiling batches included
+        canvas.set_sampler(match self.nearest_sampling {
+            true => graphics::Sampler::nearest_clamp(),
+            false => graphics::Sampler::linear_clamp(),
+        });
+
+        // ---- THIS IS WHERE THE RAYCASTING HAPPENS ----
+        // Algorithm courtesy of Lode's Computer Graphics Tutorial
+        // https://lodev.org/cgtutor/raycasting.html
+        // Rustified and adapted by me
+        let horizon = self.player.horizon(y_resolution);
+        match &self.level.ceiling_mode {
+            CeilingMode::Sky(sky) => self.draw_sky(&mut canvas, sky, horizon),
+            CeilingMode::Gradient => self.draw_gradient(&mut canvas, horizon, 0.0),
+            // Tiles open to the sky leave holes in the ceiling, which the gradient shows through
+            CeilingMode::Tiled if !self.level.sky_map.is_empty() => self.draw_gradient(&mut canvas, horizon, 0.0),
+            CeilingMode::Tiled | CeilingMode::Parallax(_) => (),
+        }
+        if let FloorMode::Gradient = self.level.floor_mode {
+            self.draw_gradient(&mut canvas, horizon, y_resolution);
+        }
+        // --- Cast rays ---
+        if self.player.rays_dirty {
+            self.update_ray_directions();
+        }
+        // Casting is pure, so it runs in parallel while drawing stays on this thread
+        let (map, doors, secret_walls, position) = (&self.level.map, &self.level.doors, &self.level.secret_walls, self.player.position);
+        let render_distance = self.config.render_distance;
+        let column_hits = self.ray_directions
+            .par_iter()
+            .map(|ray_direction| cast_ray_through(map, doors, secret_walls, position, *ray_direction, render_distance))
+            .collect::<Vec<Vec<RayHit>>>();
+        // Every wall a ray reaches has been seen, which is only a lookup per wall drawn
+        for hit in column_hits.iter().flatten() {
+            let (x, y) = hit.tile;
+            if x < 0 || y < 0 {
+                continue;
+            }
+            if let Some(explored) = self.explored.get_mut(y as usize).and_then(|row| row.get_mut(x as usize)) {
+                *explored = true;
+            }
+        }
+        let flashlight_intensity = self.flashlight.then_some(self.config.flashlight_intensity);
+        // --- Create wall batches ---
+        for batch in self.gfx.wall_batches.iter_mut().flatten() {
+            batch.clear();
+        }
+        for (depth, hits) in self.z_buffer.iter_mut().zip(&column_hits) {
+            *depth = sprite_depth(hits);
+        }
+        // Columns without any wall in range leave the whole column to the floor and ceiling
+        self.wall_spans.fill((horizon, horizon));
+        let mut wall_slices = 0;
+        let mut wall_draw_calls = 0;
+        let (gfx, wall_spans, elapsed) = (&mut self.gfx, &mut self.wall_spans, self.elapsed);
+        cast_walls(&self.level, &self.config, &self.player, &self.ray_directions, &column_hits, flashlight_intensity, |slice| {
+            match slice.batched {
+                true => gfx.wall_batch(slice.texture_index, elapsed).push(slice.params),
+                false => {
+                    canvas.draw(gfx.wall_texture(slice.texture_index).frame(elapsed), slice.params);
+                    wall_draw_calls += 1;
+                }
+            }
+            wall_slices += 1;
+            wall_spans[slice.column] = slice.span;
+        });
+        for batch in self.gfx.wall_batches.iter().flatten().filter(|batch| !batch.instances().is_empty()) {
+            // Above the sky, floor and ceiling, which are drawn at the very bottom
+            canvas.draw(batch, DrawParam::new().z(i32::MIN + 1));
+            wall_draw_calls += 1;
+        }
+
+        // --- Create floor/ceiling batches ---
+        for batch in self.gfx.floor_batches.iter_mut().chain(self.gfx.ceiling_batches.iter_mut()) {
+            batch.clear();
+        }
+        self.gfx.liquid_batch.clear();
+        let gfx = &mut self.gfx;
+        cast_flats(&self.level, &self.config, &self.player, &self.wall_spans, flashlight_intensity, self.elapsed, |batch, params| {
+            let (batches, texture_index) = match batch {
+                FlatBatch::Floor(texture_index) => (&mut gfx.floor_batches, texture_index),
+                FlatBatch::Ceiling(texture_index) => (&mut gfx.ceiling_batches, texture_index),
+                FlatBatch::Liquid => return gfx.liquid_batch.push(params),
+            };
+            let last = batches.len() - 1;
+            batches[texture_index.min(last)].push(params);
+        });
+
+        // -- Draw decorations, items and enemies --
+        let mut sprites_drawn = 0;
+        let mut sprites = self.level.decorations_in_view(&self.player, self.config.render_distance)
+            .into_iter()
+            .map(|decoration| decoration as &dyn Sprite)
+            .chain(self.level.items.iter().map(|item| &item.decoration as &dyn Sprite))
+            .chain(self.level.enemies.iter().map(|enemy| enemy as &dyn Sprite))
+            .chain(self.particles.iter().map(|particle| particle as &dyn Sprite))
+            .collect::<Vec<&dyn Sprite>>();
+        // Draw far to near so overlapping sprites blend correctly
+        sort_far_to_near(&mut sprites, self.player.position, |sprite| sprite.position());
+        // Sprites are loaded premultiplied, see `load_sprite`
+        canvas.set_blend_mode(BlendMode::PREMULTIPLIED);
+        for item in sprites {
+            if item.draw(&mut canvas, &self.player, &self.config, &self.z_buffer) {
+                sprites_drawn += 1;
+            }
+        }
+
+        // -- Draw batched textures --
+        // floor and ceiling
+        for batch in self.gfx.floor_batches.iter().chain(self.gfx.ceiling_batches.iter()) {
+            canvas.draw(batch, DrawParam::new().z(i32::MIN));
+        }
+        // Over the floor, drawn in the same layer
+        canvas.draw(&self.gfx.liquid_batch, DrawParam::new().z(i32::MIN));
+        self.weapons[self.current_weapon].draw(&mut canvas, &self.player, &self.config, self.weapon_lowered);
+        canvas.set_blend_mode(BlendMode::ALPHA);
+        canvas.set_screen_coordinates(Rect::new(0.0, 0.0, x_resolution, y_resolution));
+        if self.muzzle_flash > 0.0 {
+            let flash_params = DrawParam::new()
+            .scale(vec2(x_resolution, y_resolution))
+            .color(Color::new(1.0, 0.9, 0.6, 0.25))
+            .z(1);
+            canvas.draw(&Quad, flash_params);
+        }
+        if self.damage_flash > 0.0 {
+            let flash_params = DrawParam::new()
+            .scale(vec2(x_resolution, y_resolution))
+            .color(Color::new(1.0, 0.0, 0.0, 0.4 * self.damage_flash / DAMAGE_FLASH_DURATION))
+            .z(1);
+            canvas.draw(&Quad, flash_params);
+        }
+        // Draw FPS counter
+        if self.show_hud {
+            self.draw_crosshair(&mut canvas);
+            self.draw_health_bar(&mut canvas);
+            let fps = self.time_context.fps();
+            let noclip_indicator = match self.noclip {
+                true => " NOCLIP",
+                false => "",
+            };
+            let inventory = &self.player.inventory;
+            let mut hud_text = format!("{:.2}{}\nCoins: {}  Keys: {}", fps, noclip_indicator, inventory.coins, inventory.keys.len());
+            if self.show_debug {
+                hud_text += &self.debug_info(sprites_drawn, wall_slices, wall_draw_calls);
+            }
+            let hud = Text::new(hud_text);
+            if self.show_debug {
+                // Keep the text readable against bright floors
+                let size = hud.measure(ctx)?;
+                let backing_params = DrawParam::new()
+                .scale(vec2(size.x + 4.0, size.y + 4.0))
+                .color(Color::new(0.0, 0.0, 0.0, 0.5));
+                canvas.draw(&Quad, backing_params);
+            }
+            canvas.draw(&hud, DrawParam::new().dest(vec2(0.0, 0.0)).z(1));
+        }
+        if self.show_minimap {
+            self.draw_minimap(&mut canvas);
+        }
+        self.draw_messages(ctx, &mut canvas)?;
+        if self.player.is_dead() {
+            self.draw_death_screen(ctx, &mut canvas)?;
+        }
+        if self.fade > 0.0 {
+            let fade_params = DrawParam::new()
+            .scale(vec2(x_resolution, y_resolution))
+            .color(Color::new(0.0, 0.0, 0.0, self.fade))
+            .z(3);
+            canvas.draw(&Quad, fade_params);
+        }
+        if self.paused {
+            self.draw_pause_menu(ctx, &mut canvas)?;
+        }
+        canvas.finish(ctx)
+    }
+
+    /// Reads back the last rendered frame as RGBA pixels
+    fn frame_pixels(&self, ctx: &Context) -> Result<Vec<u8>, GameError> {
+        let mut pixels = self.frame.to_pixels(ctx)?;
+        match self.frame.format() {
+            ImageFormat::Rgba8Unorm | ImageFormat::Rgba8UnormSrgb => (),
+            ImageFormat::Bgra8Unorm | ImageFormat::Bgra8UnormSrgb => {
+                pixels.chunks_exact_mut(4).for_each(|pixel| pixel.swap(0, 2));
+            }
+            format => return Err(GameError::RenderError(format!("Can't read back frames in the {format:?} format"))),
+        }
+        Ok(pixels)
+    }
+
+    /// Saves the last rendered frame as a PNG
+    fn save_frame(&self, ctx: &Context, path: &str) -> Result<(), GameError> {
+        image::save_buffer(path, &self.frame_pixels(ctx)?, self.frame.width(), self.frame.height(), image::ColorType::Rgba8)
+            .map_err(|error| GameError::CustomError(format!("Failed saving {path}: {error}")))
+    }
+
+    /// Saves the last rendered frame as a PNG in `SCREENSHOT_DIRECTORY`, returning its path
+    fn save_screenshot(&self, ctx: &Context) -> Result<String, GameError> {
+        let timestamp = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_millis();
+        let path = format!("{SCREENSHOT_DIRECTORY}/shot_{timestamp}.png");
+        std::fs::create_dir_all(SCREENSHOT_DIRECTORY)?;
+        self.save_frame(ctx, &path)?;
+        Ok(path)
+    }
+
+    /// Compares the last rendered frame to a golden image.
+    /// Returns the mean difference per color channel, from 0 to 1.
+    fn frame_difference(&self, ctx: &Context, golden_path: &str) -> Result<f32, GameError> {
+        let golden = image::open(golden_path)
+            .map_err(|error| GameError::ResourceLoadError(format!("Failed loading golden image {golden_path}: {error}")))?
+            .to_rgba8();
+        if golden.dimensions() != (self.frame.width(), self.frame.height()) {
+            return Err(GameError::CustomError(format!(
+                "Golden image {golden_path} is {}x{}, but frames are {}x{}",
+                golden.width(), golden.height(), self.frame.width(), self.frame.height()
+            )));
+        }
+        let pixels = self.frame_pixels(ctx)?;
+        let total_difference = pixels
+            .iter()
+            .zip(golden.as_raw())
+            .map(|(a, b)| a.abs_diff(*b) as f32)
+            .sum::<f32>();
+        Ok(total_difference / (pixels.len() as f32 * 255.0))
+    }
+
+    /// The extra lines of the debug overlay, below the FPS counter
+    fn debug_info(&self, sprites_drawn: usize, wall_slices: usize, wall_draw_calls: usize) -> String {
+        let position = self.player.position;
+        let (tile_x, tile_y) = (position.x.floor() as i32, position.y.floor() as i32);
+        let tile = match self.level.tile_at(tile_x, tile_y) {
+            Some(tile) => format!("wall {:?}", tile.textures),
+            None => format!("floor {}", layer_at(&self.level.floor_map, tile_x, tile_y)),
+        };
+        let angle = self.player.direction.y.atan2(self.player.direction.x).to_degrees().rem_euclid(360.0);
+        let fov = 2.0 * self.player.fov().atan().to_degrees();
+        format!(
+            "\nPosition: {:.2}, {:.2}\nAngle: {:.0}\nFOV: {:.0}\nTile: {}, {} ({})\nSprites: {}\nWall slices: {} in {} draw calls",
+            position.x, position.y, angle, fov, tile_x, tile_y, tile, sprites_drawn, wall_slices, wall_draw_calls
+        )
+    }
+
+    /// Scales the rendered frame up to fill as much of the window as possible without distorting it.
+    /// Whole-number scales are preferred so every pixel ends up the same size, and any space left over
+    /// is filled with black bars.
+    fn present_frame(&self, ctx: &mut Context) -> Result<(), GameError> {
+        // A minimized window has nowhere to draw to
+        let Some((scale, offset)) = self.frame_placement else {
+            return Ok(());
+        };
+        let mut canvas = graphics::Canvas::from_frame(ctx, Color::BLACK);
+        canvas.set_sampler(graphics::Sampler::nearest_clamp());
+        canvas.draw(&self.frame, DrawParam::new().dest(offset).scale(vec2(scale, scale)));
+        canvas.finish(ctx)
+    }
+
+    /// Shakes the view, adding `amount` to the current shake up to a full shake of 1
+    fn add_shake(&mut self, amount: f32) {
+        self.shake = (self.shake + amount).min(1.0);
+    }
+
+    /// Puts up a line of text near the bottom of the screen for `duration` seconds, below any already up
+    fn show_message<T: Into<String>>(&mut self, text: T, duration: f32) {
+        if self.messages.len() == MAX_MESSAGES {
+            self.messages.pop_front();
+        }
+        self.messages.push_back((text.into(), duration));
+    }
+
+    /// Draws the messages that are up, stacked upwards from near the bottom of the screen with the newest last
+    fn draw_messages(&self, ctx: &Context, canvas: &mut Canvas) -> Result<(), GameError> {
+        let (x_resolution, y_resolution) = (self.config.x_resolution, self.config.y_resolution);
+        let mut bottom = y_resolution - 16.0;
+        for (message, _) in self.messages.iter().rev() {
+            let text = Text::new(message.as_str());
+            let size = text.measure(ctx)?;
+            let position = vec2((x_resolution - size.x) / 2.0, bottom - size.y).floor();
+            let backing_params = DrawParam::new()
+            .dest(position - vec2(2.0, 1.0))
+            .scale(vec2(size.x + 4.0, size.y + 2.0))
+            .color(Color::new(0.0, 0.0, 0.0, 0.5))
+            .z(1);
+            canvas.draw(&Quad, backing_params);
+            canvas.draw(&text, DrawParam::new().dest(position).z(1));
+            bottom = position.y - 3.0;
+        }
+        Ok(())
+    }
+
+    /// Draws the player's health as a bar in the bottom left corner
+    fn draw_health_bar(&self, canvas: &mut Canvas) {
+        let size = vec2(64.0, 6.0);
+        let position = vec2(4.0, self.config.y_resolution - size.y - 4.0);
+        let backing_params = DrawParam::new()
+        .dest(position)
+        .scale(size)
+        .color(Color::new(0.0, 0.0, 0.0, 0.5))
+        .z(1);
+        canvas.draw(&Quad, backing_params);
+        let fill = self.player.health / PLAYER_MAX_HEALTH;
+        let bar_params = DrawParam::new()
+        .dest(position + Vec2::ONE)
+        .scale(vec2((size.x - 2.0) * fill, size.y - 2.0))
+        .color(Color::new(1.0 - fill, fill, 0.0, 1.0))
+        .z(1);
+        canvas.draw(&Quad, bar_params);
+    }
+
+    /// Darkens the screen and tells the player how to restart
+    fn draw_death_screen(&self, ctx: &Context, canvas: &mut Canvas) -> Result<(), GameError> {
+        let (x_resolution, y_resolution) = (self.config.x_resolution, self.config.y_resolution);
+        let overlay_params = DrawParam::new()
+        .scale(vec2(x_resolution, y_resolution))
+        .color(Color::new(0.3, 0.0, 0.0, 0.6))
+        .z(2);
+        canvas.draw(&Quad, overlay_params);
+        let prompt = match self.config.keys.reload_level {
+            Some(key) => format!("You Died\nPress {key:?} to restart"),
+            None => String::from("You Died"),
+        };
+        let text = Text::new(prompt);
+        let size = text.measure(ctx)?;
+        let position = vec2(x_resolution - size.x, y_resolution - size.y) / 2.0;
+        canvas.draw(&text, DrawParam::new().dest(position.floor()).z(2));
+        Ok(())
+    }
+
+    /// Draws a small plus in the middle of the screen, where shots go
+    fn draw_crosshair(&self, canvas: &mut Canvas) {
+        let center = (vec2(self.config.x_resolution, self.config.y_resolution) / 2.0).floor();
+        for scale in [vec2(CROSSHAIR_SIZE, 1.0), vec2(1.0, CROSSHAIR_SIZE)] {
+            let line_params = DrawParam::new()
+            .dest(center - (scale - Vec2::ONE) / 2.0)
+            .scale(scale)
+            .color(Color::new(1.0, 1.0, 1.0, 0.8))
+            .z(1);
+            canvas.draw(&Quad, line_params);
+        }
+    }
+
+    /// Pauses or unpauses the game, releasing the cursor and saving any changed settings while paused
+    fn set_paused(&mut self, ctx: &mut Context, paused: bool) -> Result<(), GameError> {
+        if paused {
+            self.save_settings();
+        }
+        self.paused = paused;
+        self.pause_selection = 0;
+        mouse::set_cursor_grabbed(ctx, !paused)?;
+        mouse::set_cursor_hidden(ctx, !paused);
+        // The mouse moved the free cursor in the meantime, so don't read that as a turn
+        self.raw_mouse_delta = Vec2::ZERO;
+        Ok(())
+    }
+
+    fn handle_pause_menu(&mut self, ctx: &mut Context) -> Result<(), GameError> {
+        if self.input.was_pressed(Some(KeyCode::Up)) {
+            self.pause_selection = (self.pause_selection + PAUSE_MENU.len() - 1) % PAUSE_MENU.len();
+        }
+        if self.input.was_pressed(Some(KeyCode::Down)) {
+            self.pause_selection = (self.pause_selection + 1) % PAUSE_MENU.len();
+        }
+        if self.input.was_pressed(Some(KeyCode::Return)) {
+            match PAUSE_MENU[self.pause_selection] {
+                PauseOption::Resume => self.set_paused(ctx, false)?,
+                PauseOption::Quit => ctx.request_quit(),
+            }
+        }
+        Ok(())
+    }
+
+    /// Dims the world and draws the pause menu over it, marking the selected option
+    fn draw_pause_menu(&self, ctx: &Context, canvas: &mut Canvas) -> Result<(), GameError> {
+        let dim_params = DrawParam::new()
+        .scale(vec2(self.config.x_resolution, self.config.y_resolution))
+        .color(Color::new(0.0, 0.0, 0.0, 0.6))
+        .z(i32::MAX);
+        canvas.draw(&Quad, dim_params);
+        let mut menu_text = String::from("Paused\n");
+        for (index, option) in PAUSE_MENU.iter().enumerate() {
+            let marker = match index == self.pause_selection {
+                true => "> ",
+                false => "  ",
+            };
+            menu_text += &format!("\n{}{}", marker, option.label());
+        }
+        let menu = Text::new(menu_text);
+        let size = menu.measure(ctx)?;
+        let position = vec2(self.config.x_resolution - size.x, self.config.y_resolution - size.y) / 2.0;
+        canvas.draw(&menu, DrawParam::new().dest(position.floor()).z(i32::MAX));
+        Ok(())
+    }
+
+    /// Draws a top-down view of the map with the player on it
+    fn draw_minimap(&self, canvas: &mut Canvas) {
+        let scale = self.config.minimap_scale;
+        let origin = Vec2::from(self.config.minimap_position);
+        for (y, row) in self.level.map.iter().enumerate() {
+            for (x, tile) in row.iter().enumerate() {
+                if tile.is_none() || !self.explored[y][x] {
+                    continue;
+                }
+                let params = DrawParam::new()
+                .dest(origin + vec2(x as f32, y as f32) * scale)
+                .scale(vec2(scale, scale))
+                .color(Color::new(1.0, 1.0, 1.0, 0.6))
+                .z(i32::MAX);
+                canvas.draw(&Quad, params);
+            }
+        }
+        // Player dot and a short line showing where they're facing
+        let player_position = origin + self.player.position * scale;
+        let dot_params = DrawParam::new()
+        .dest(player_position)
+        .offset(vec2(0.5, 0.5))
+        .scale(vec2(scale * 0.5, scale * 0.5))
+        .color(Color::RED)
+        .z(i32::MAX);
+        canvas.draw(&Quad, dot_params);
+        let line_params = DrawParam::new()
+        .dest(player_position)
+        .offset(vec2(0.0, 0.5))
+        .rotation(self.player.direction.y.atan2(self.player.direction.x))
+        .scale(vec2(scale * 1.5, 1.0))
+        .color(Color::RED)
+        .z(i32::MAX);
+        canvas.draw(&Quad, line_params);
+    }
+
+}
+
+impl RawMouseHandler for GameState {
+    fn raw_mouse_motion_event(&mut self, _ctx: &mut Context, dx: f32, dy: f32) {
+        self.raw_mouse_delta += vec2(dx, dy);
+    }
+}
+
+impl event::EventHandler for GameState {
+    fn update(&mut self, ctx: &mut Context) -> Result<(), GameError> {
+        let input = match &mut self.replay {
+            Some(replay) => replay.next_frame(),
+            None => Some(self.read_input(ctx)),
+        };
+        let Some(input) = input else {
+            // The demo is over
+            ctx.request_quit();
+            return Ok(());
+        };
+        if let Some(recorder) = &mut self.recorder {
+            recorder.record(&input);
+        }
+        self.input = input;
+
+        if self.input.was_pressed(self.config.keys.pause) {
+            self.set_paused(ctx, !self.paused)?;
+        }
+        // Nothing advances while paused, so unpausing picks up right where the game left off
+        if self.paused {
+            return self.handle_pause_menu(ctx);
+        }
+        let delta = self.input.delta.min(MAX_DELTA);
+        self.elapsed += delta;
+        for decoration in &mut self.level.decorations {
+            decoration.advance(delta);
+            decoration.update_sound(&self.player);
+        }
+        for item in &mut self.level.items {
+            item.decoration.advance(delta);
+        }
+        for enemy in &mut self.level.enemies {
+            enemy.advance(delta);
+        }
+        for particle in &mut self.particles {
+            particle.advance(delta);
+        }
+        self.particles.retain(|particle| !particle.is_expired());
+        self.muzzle_flash = (self.muzzle_flash - delta).max(0.0);
+        for weapon in &mut self.weapons {
+            weapon.advance(delta);
+        }
+        self.advance_weapon_switch(delta);
+        for (_, time_left) in &mut self.messages {
+            *time_left -= delta;
+        }
+        self.messages.retain(|(_, time_left)| *time_left > 0.0);
+        self.damage_flash = (self.damage_flash - delta).max(0.0);
+        self.shake = (self.shake - SHAKE_DECAY * delta).max(0.0);
+        self.advance_transition(ctx, delta);
+        // The dead can only restart
+        if self.player.is_dead() {
+            if self.input.was_pressed(self.config.keys.reload_level) {
+                self.start_level_change();
+            }
+            return Ok(());
+        }
+        for _ in 0..ticks_due(&mut self.tick_accumulator, delta) {
+            self.tick(ctx);
+        }
+        self.handle_input();
+        self.handle_mouse();
+
+        Ok(())
+    }
+
+    fn key_down_event(&mut self, _ctx: &mut Context, _input: KeyInput, _repeated: bool) -> Result<(), GameError> {
+        // Overridden so Escape pauses instead of quitting
+        Ok(())
+    }
+
+    fn quit_event(&mut self, _ctx: &mut Context) -> Result<bool, GameError> {
+        self.save_settings();
+        if let Some(recorder) = &self.recorder {
+            match recorder.save() {
+                Ok(()) => println!("Recorded {} frames of input", recorder.frame_count()),
+                Err(error) => eprintln!("{error}"),
+            }
+        }
+        if self.replay.is_some() {
+            println!("Replay finished at {}", self.player.position);
+        }
+        Ok(false)
+    }
+
+    fn resize_event(&mut self, _ctx: &mut Context, width: f32, height: f32) -> Result<(), GameError> {
+        // The internal resolution stays fixed, only the way the frame is scaled up changes
+        self.frame_placement = place_frame(vec2(width, height), vec2(self.config.x_resolution, self.config.y_resolution));
+        Ok(())
+    }
+
+    fn focus_event(&mut self, _ctx: &mut Context, gained: bool) -> Result<(), GameError> {
+        self.has_focus = gained;
+        Ok(())
+    }
+
+    fn draw(&mut self, ctx: &mut Context) -> Result<(), GameError> {
+        self.time_context.tick();
+
+        // Draw the player partway between the last two ticks, so movement looks smooth at any frame rate
+        let simulated_position = self.player.position;
+        let alpha = self.tick_accumulator / TICK_LENGTH;
+        self.player.position = self.previous_position.lerp(simulated_position, alpha);
+        let result = self.render(ctx);
+        self.player.position = simulated_position;
+        self.limit_frame_rate();
+        result
+    }
+}
+
+/// Adds `delta` seconds to the time not yet simulated in `accumulator`, and takes out as many whole ticks as fit.
+/// Returns how many ticks that is, leaving the remainder for the next frame.
+fn ticks_due(accumulator: &mut f32, delta: f32) -> u32 {
+    *accumulator += delta;
+    let mut ticks = 0;
+    while *accumulator >= TICK_LENGTH {
+        *accumulator -= TICK_LENGTH;
+        ticks += 1;
+    }
+    ticks
+}
+
+/// An explored map the size of `map`, with nothing explored yet
+fn unexplored(map: &[Vec<Option<Tile>>]) -> Vec<Vec<bool>> {
+    map.iter().map(|row| vec![false; row.len()]).collect()
+}
+
+/// Ignores stick positions within `deadzone` of the center, and rescales the rest
+/// so the stick still goes smoothly from zero to full
+fn apply_deadzone(stick: Vec2, deadzone: f32) -> Vec2 {
+    let magnitude = stick.length();
+    if magnitude <= deadzone {
+        return Vec2::ZERO;
+    }
+    stick / magnitude * ((magnitude - deadzone) / (1.0 - deadzone)).min(1.0)
+}
+
+/// A small xorshift random number generator, for effects that should play out
+/// the same way every run given the same seed
+struct Rng {
+    state: u64,
+}
+
+impl Rng {
+    fn new(seed: u64) -> Rng {
+        // Xorshift gets stuck at zero
+        Rng { state: seed.max(1) }
+    }
+
+    fn next_u64(&mut self) -> u64 {
+        self.state ^= self.state << 13;
+        self.state ^= self.state >> 7;
+        self.state ^= self.state << 17;
+        self.state
+    }
+
+    /// A random number from 0 up to but not including 1
+    fn next_f32(&mut self) -> f32 {
+        // The top 24 bits are as many as an f32 can hold exactly
+        (self.next_u64() >> 40) as f32 / (1u64 << 24) as f32
+    }
+}
+
+/// Renders a single frame from the spawn without running the game, for regression testing the renderer.
+/// Saves the frame to `output_path` and, if given, checks it against the golden image at `golden_path`.
+pub fn run_headless(ctx: &mut Context, state: &mut GameState, output_path: &str, golden_path: Option<&str>) -> Result<(), GameError> {
+    let (level, spawn_position, spawn_direction) = load_level(ctx, &state.config)?;
+    let player = Player::new(spawn_position, spawn_direction, state.config.camera_plane_length());
+    state.render_frame(ctx, level, player)?;
+    state.save_frame(ctx, output_path)?;
+    let Some(golden_path) = golden_path else {
+        return Ok(());
+    };
+    let difference = state.frame_difference(ctx, golden_path)?;
+    if difference > GOLDEN_TOLERANCE {
+        return Err(GameError::CustomError(format!(
+            "Frame differs from {golden_path} by {difference:.4}, more than the allowed {GOLDEN_TOLERANCE}"
+        )));
+    }
+    println!("Frame matches {golden_path} (difference {difference:.4})");
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::player::COLLISION_RADIUS;
+    use crate::level::tests::level;
+    use crate::player::tests::player;
+
+    #[test]
+    fn leftover_time_carries_over_to_the_next_frame() {
+        let mut accumulator = 0.0;
+        assert_eq!(ticks_due(&mut accumulator, TICK_LENGTH * 0.75), 0);
+        assert_eq!(ticks_due(&mut accumulator, TICK_LENGTH * 0.75), 1);
+        assert!((accumulator - TICK_LENGTH * 0.5).abs() < 0.0001);
+        assert_eq!(ticks_due(&mut accumulator, MAX_DELTA), 2);
+    }
+
+    #[test]
+    fn ticks_alone_keep_collision_reliable() {
+        let room = format!("S.S{}S", ".".repeat(40));
+        let level = level(&[&"S".repeat(44), &room, &"S".repeat(44)]);
+        let config = Config::default();
+        let mut player = player(vec2(1.5, 1.5));
+        // Walking into a wall through a ten second hitch, simulated in fixed ticks like `update` does.
+        // Nothing caps the hitch here, so this checks the ticks are short enough on their own
+        let mut accumulator = 0.0;
+        for _ in 0..ticks_due(&mut accumulator, 10.0) {
+            player.accelerate_towards(vec2(config.move_speed, 0.0), TICK_LENGTH);
+            player.try_move(player.velocity * TICK_LENGTH, &level);
+        }
+        assert!(player.position.x <= 2.0 - COLLISION_RADIUS);
+    }
+}